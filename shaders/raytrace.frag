@@ -92,7 +92,10 @@ bool raymarch(vec3 o, vec3 d, float bvh_t_min, out float o_t, out vec3 o_color,
         float tc_max = min(min(t_corner.x, t_corner.y), t_corner.z);
 
         // Process voxel if it exists and the active t-span is non-empty.
-        uint child_idx = (cur_node & 0x3fffffff) * 2 + (idx ^ oct_mask) * 2;
+        // Far nodes hold the offset to a slot with the child tile index.
+        uint child_tile = cur_node & 0x1fffffffu;
+        if((cur_node & 0x20000000u) != 0) child_tile = svo_data[parent_idx + child_tile * 2];
+        uint child_idx = child_tile * 2 + (idx ^ oct_mask) * 2;
         uint child = svo_data[child_idx];
 
         if((child & 0x80000000u) == 0 && t_min <= t_max )
@@ -0,0 +1,206 @@
+use std::convert::TryFrom;
+
+use crate::raycast::{RayQuery, RaycastHit, Raycastable};
+use crate::svo::{
+    child_tile, is_empty, is_leaf, raycast_octree, BuildError, SparseVoxelOctree, Traversable,
+};
+
+// Child descriptor in the spirit of Laine and Karras:
+// node[0] = valid mask (bits 0..7) | leaf mask (bits 8..15) | far (bit 16) | child offset (bits 17..31)
+// node[1] = colour
+//
+// Only the children that exist are stored, contiguously and in slot order.
+// Child blocks are written before their parent, so the offset counts backwards
+// from the node to its first child. If it does not fit into 15 bits, the far
+// bit is set and the offset counts forwards to a far pointer instead, whose two
+// words hold the absolute index of the child block.
+type CompactNode = [u32; 2];
+
+const MAX_OFFSET: usize = (1 << 15) - 1;
+const FAR_BIT: u32 = 1 << 16;
+
+/// Octree that only stores existing children instead of full tiles of eight,
+/// at the cost of a few bit operations per child lookup.
+pub struct CompactSparseVoxelOctree {
+    pub node_pool: Vec<CompactNode>,
+    pub root: usize,
+    pub size: usize,
+}
+
+// Points the node at `node_idx` to its child block, through a far pointer
+// appended to the pool if the block is too far away.
+fn link(
+    node_pool: &mut Vec<CompactNode>,
+    node_idx: usize,
+    children: usize,
+    valid: u32,
+    leaf: u32,
+) -> Result<(), BuildError> {
+    let masks = valid | (leaf << 8);
+    let offset = node_idx - children;
+    node_pool[node_idx][0] = if offset <= MAX_OFFSET {
+        masks | (offset << 17) as u32
+    } else {
+        let far_offset = node_pool.len() - node_idx;
+        if far_offset > MAX_OFFSET {
+            return Err(BuildError::PointerOutOfRange {
+                node: node_idx,
+                children,
+            });
+        }
+        node_pool.push([children as u32, (children as u64 >> 32) as u32]);
+        masks | FAR_BIT | (far_offset << 17) as u32
+    };
+    Ok(())
+}
+
+// Writes the existing children of the octree tile at `tile_idx` after all of
+// their descendants. Returns the index of the child block, the valid mask and
+// the leaf mask.
+fn write_children(
+    svo: &SparseVoxelOctree,
+    tile_idx: usize,
+    node_pool: &mut Vec<CompactNode>,
+) -> Result<(usize, u32, u32), BuildError> {
+    let tile = &svo.node_pool[tile_idx..tile_idx + 8];
+    let mut grandchildren = [(0, 0, 0); 8];
+    for (slot, node) in tile.iter().enumerate() {
+        if !is_empty(node) && !is_leaf(node) {
+            grandchildren[slot] =
+                write_children(svo, child_tile(&svo.node_pool, tile_idx + slot), node_pool)?;
+        }
+    }
+
+    let block_idx = node_pool.len();
+    let mut valid = 0;
+    let mut leaf = 0;
+    for (slot, node) in tile.iter().enumerate() {
+        if !is_empty(node) {
+            valid |= 1 << slot;
+            leaf |= (is_leaf(node) as u32) << slot;
+            node_pool.push([0, node[1]]);
+        }
+    }
+    // leaves have no children and keep an empty descriptor
+    let mut node_idx = block_idx;
+    for (slot, node) in tile.iter().enumerate() {
+        if !is_empty(node) {
+            if !is_leaf(node) {
+                let (children, valid, leaf) = grandchildren[slot];
+                link(node_pool, node_idx, children, valid, leaf)?;
+            }
+            node_idx += 1;
+        }
+    }
+    Ok((block_idx, valid, leaf))
+}
+
+// Fails if a node cannot reach its child block, not even through a far pointer.
+impl TryFrom<&SparseVoxelOctree> for CompactSparseVoxelOctree {
+    type Error = BuildError;
+
+    fn try_from(svo: &SparseVoxelOctree) -> Result<CompactSparseVoxelOctree, BuildError> {
+        let mut node_pool = Vec::new();
+        let root = svo.node_pool[0];
+        let (children, valid, leaf) =
+            write_children(svo, child_tile(&svo.node_pool, 0), &mut node_pool)?;
+        let root_idx = node_pool.len();
+        node_pool.push([0, root[1]]);
+        link(&mut node_pool, root_idx, children, valid, leaf)?;
+        Ok(CompactSparseVoxelOctree {
+            node_pool,
+            root: root_idx,
+            size: svo.size,
+        })
+    }
+}
+
+impl CompactSparseVoxelOctree {
+    pub fn size_bytes(&self) -> usize {
+        std::mem::size_of::<CompactNode>() * self.node_pool.len()
+    }
+
+    fn child_block(&self, node_idx: usize) -> usize {
+        let descriptor = self.node_pool[node_idx][0];
+        let offset = (descriptor >> 17) as usize;
+        if descriptor & FAR_BIT != 0 {
+            let far_pointer = self.node_pool[node_idx + offset];
+            (far_pointer[0] as u64 | (far_pointer[1] as u64) << 32) as usize
+        } else {
+            node_idx - offset
+        }
+    }
+}
+
+// Leaves have no children, so whether a node is one is taken from the leaf
+// mask of its parent and kept in the reference.
+impl Traversable for CompactSparseVoxelOctree {
+    type Ref = (usize, bool);
+
+    fn root(&self) -> (usize, bool) {
+        (self.root, false)
+    }
+
+    fn child(&self, (node_idx, _): (usize, bool), slot: u32) -> Option<(usize, bool)> {
+        let descriptor = self.node_pool[node_idx][0];
+        let valid = descriptor & 0xff;
+        if valid & (1 << slot) == 0 {
+            return None;
+        }
+        let rank = (valid & ((1 << slot) - 1)).count_ones() as usize;
+        let is_leaf = (descriptor >> 8) & (1 << slot) != 0;
+        Some((self.child_block(node_idx) + rank, is_leaf))
+    }
+
+    fn is_leaf(&self, (_, is_leaf): (usize, bool)) -> bool {
+        is_leaf
+    }
+
+    fn color(&self, (node_idx, _): (usize, bool)) -> u32 {
+        self.node_pool[node_idx][1]
+    }
+
+    fn index(&self, (node_idx, _): (usize, bool)) -> usize {
+        node_idx
+    }
+
+    fn size(&self) -> usize {
+        self.size
+    }
+}
+
+impl Raycastable for CompactSparseVoxelOctree {
+    fn raycast_query(&self, query: &RayQuery) -> Option<RaycastHit> {
+        raycast_octree(self, query)
+    }
+}
+
+#[test]
+fn test_compact_svo() {
+    use crate::svo::{assert_same_raycasts, random_voxels};
+    use rand::SeedableRng;
+
+    fn count_far_pointers(compact: &CompactSparseVoxelOctree, node: (usize, bool)) -> usize {
+        if compact.is_leaf(node) {
+            assert_eq!(compact.node_pool[node.0][0], 0);
+            return 0;
+        }
+        let far = (compact.node_pool[node.0][0] & FAR_BIT != 0) as usize;
+        far + (0..8)
+            .filter_map(|slot| compact.child(node, slot))
+            .map(|child| count_far_pointers(compact, child))
+            .sum::<usize>()
+    }
+
+    let mut rng = rand::rngs::StdRng::seed_from_u64(9);
+    for &(size, count) in &[(2, 0), (2, 3), (32, 1000), (64, 100_000)] {
+        let voxels: Vec<_> = random_voxels(&mut rng, size, count);
+        let svo = SparseVoxelOctree::from_voxels(size, voxels).unwrap();
+        let compact = CompactSparseVoxelOctree::try_from(&svo).unwrap();
+        assert!(compact.size_bytes() < svo.size_bytes());
+        assert_same_raycasts(&svo, &compact, size as u64);
+        // only the largest octree is big enough to need far pointers
+        let far_pointers = count_far_pointers(&compact, compact.root());
+        assert_eq!(far_pointers > 0, size == 64);
+    }
+}
@@ -0,0 +1,205 @@
+use std::collections::HashMap;
+
+use crate::svo::{
+    child_tile, copy_subtree, create_node, is_empty, is_leaf, link_child, node_children, root_pool,
+    write_children, BuildError, NodeRef, SVONode, SparseVoxelOctree,
+};
+
+#[derive(Clone, Copy)]
+enum CsgOp {
+    Union,
+    Intersection,
+    Difference,
+}
+
+// Combines two octrees into a new node pool. Subtrees that end up unchanged
+// in the result are copied as a whole, sharing tiles that are shared in the
+// inputs.
+struct CsgBuilder<'a> {
+    op: CsgOp,
+    a: &'a [SVONode],
+    b: &'a [SVONode],
+    node_pool: Vec<SVONode>,
+    copied_a: HashMap<usize, usize>,
+    copied_b: HashMap<usize, usize>,
+}
+
+impl CsgBuilder<'_> {
+    fn copy_a(&mut self, (node, tile_idx): NodeRef) -> Result<NodeRef, BuildError> {
+        if is_empty(&node) || is_leaf(&node) {
+            return Ok((node, 0));
+        }
+        let tile_idx = copy_subtree(self.a, tile_idx, &mut self.node_pool, &mut self.copied_a)?;
+        Ok((node, tile_idx))
+    }
+
+    fn copy_b(&mut self, (node, tile_idx): NodeRef) -> Result<NodeRef, BuildError> {
+        if is_empty(&node) || is_leaf(&node) {
+            return Ok((node, 0));
+        }
+        let tile_idx = copy_subtree(self.b, tile_idx, &mut self.node_pool, &mut self.copied_b)?;
+        Ok((node, tile_idx))
+    }
+
+    // Returns the combination of node `a` of the first and node `b` of the
+    // second octree. Leaves are full cubes, so they decide the result for their
+    // whole subtree unless they are only filled up with the other side.
+    fn combine(&mut self, a: NodeRef, b: NodeRef) -> Result<NodeRef, BuildError> {
+        let empty = (create_node(), 0);
+        let (a_empty, a_leaf) = (is_empty(&a.0), is_leaf(&a.0));
+        let (b_empty, b_leaf) = (is_empty(&b.0), is_leaf(&b.0));
+        match self.op {
+            CsgOp::Union if a_empty => return self.copy_b(b),
+            CsgOp::Union if b_empty || a_leaf => return self.copy_a(a),
+            CsgOp::Intersection if a_empty || b_empty => return Ok(empty),
+            CsgOp::Intersection if b_leaf => return self.copy_a(a),
+            CsgOp::Difference if a_empty || b_leaf => return Ok(empty),
+            CsgOp::Difference if b_empty => return self.copy_a(a),
+            _ => (),
+        }
+        let children = self.combine_children(a, b)?;
+        if children.iter().all(|(child, _)| is_empty(child)) {
+            return Ok(empty);
+        }
+        write_children(&mut self.node_pool, &children)
+    }
+
+    fn combine_children(&mut self, a: NodeRef, b: NodeRef) -> Result<[NodeRef; 8], BuildError> {
+        let children_a = node_children(self.a, a);
+        let children_b = node_children(self.b, b);
+        let mut children = [(create_node(), 0); 8];
+        for digit in 0..8 {
+            let slot = 7 ^ digit;
+            children[slot] = self.combine(children_a[slot], children_b[slot])?;
+        }
+        Ok(children)
+    }
+}
+
+impl SparseVoxelOctree {
+    fn combine(
+        &self,
+        other: &SparseVoxelOctree,
+        op: CsgOp,
+    ) -> Result<SparseVoxelOctree, BuildError> {
+        if self.size != other.size {
+            return Err(BuildError::SizeMismatch(self.size, other.size));
+        }
+        let mut builder = CsgBuilder {
+            op,
+            a: &self.node_pool,
+            b: &other.node_pool,
+            node_pool: root_pool(),
+            copied_a: HashMap::new(),
+            copied_b: HashMap::new(),
+        };
+        // The root keeps its tile, even if the result is empty.
+        let root_a = (self.node_pool[0], child_tile(&self.node_pool, 0));
+        let root_b = (other.node_pool[0], child_tile(&other.node_pool, 0));
+        let children = builder.combine_children(root_a, root_b)?;
+        let (root, root_tile_idx) = write_children(&mut builder.node_pool, &children)?;
+        let mut node_pool = builder.node_pool;
+        node_pool[0] = root;
+        link_child(&mut node_pool, 0, root_tile_idx)?;
+        Ok(SparseVoxelOctree {
+            node_pool,
+            size: self.size,
+        })
+    }
+
+    /// Returns the voxels that are filled in either octree. Where both are
+    /// filled, the colour of `self` is kept.
+    pub fn union(&self, other: &SparseVoxelOctree) -> Result<SparseVoxelOctree, BuildError> {
+        self.combine(other, CsgOp::Union)
+    }
+
+    /// Returns the voxels of `self` that are filled in `other` as well.
+    pub fn intersection(&self, other: &SparseVoxelOctree) -> Result<SparseVoxelOctree, BuildError> {
+        self.combine(other, CsgOp::Intersection)
+    }
+
+    /// Returns the voxels of `self` that are not filled in `other`.
+    pub fn difference(&self, other: &SparseVoxelOctree) -> Result<SparseVoxelOctree, BuildError> {
+        self.combine(other, CsgOp::Difference)
+    }
+}
+
+#[test]
+fn test_csg() {
+    use crate::svo::{assert_same_raycasts, random_grid};
+    use crate::voxel_grid::VoxelGrid;
+    use na::Point3;
+    use rand::{Rng, SeedableRng};
+    use std::convert::TryFrom;
+
+    // random voxels and boxes, so that both sides have leaves of all sizes
+    let size = 16;
+    let mut rng = rand::rngs::StdRng::seed_from_u64(13);
+    let mut grid_a = random_grid(&mut rng, size, 0.05);
+    let mut grid_b = random_grid(&mut rng, size, 0.05);
+    let mut random_octree = |voxel_grid: &mut VoxelGrid| {
+        let mut svo = SparseVoxelOctree::try_from(&*voxel_grid).unwrap();
+        for _ in 0..20 {
+            let min = Point3::new(
+                rng.gen_range(0..size),
+                rng.gen_range(0..size),
+                rng.gen_range(0..size),
+            );
+            let max = Point3::new(
+                rng.gen_range(min.x + 1..=size),
+                rng.gen_range(min.y + 1..=size),
+                rng.gen_range(min.z + 1..=size),
+            );
+            let color = rng.gen();
+            svo.fill_box(min, max, color).unwrap();
+            for x in min.x..max.x {
+                for y in min.y..max.y {
+                    for z in min.z..max.z {
+                        voxel_grid.data[x][y][z] = Some(color);
+                    }
+                }
+            }
+        }
+        svo
+    };
+    let a = random_octree(&mut grid_a);
+    let b = random_octree(&mut grid_b);
+
+    let mut expected = [
+        VoxelGrid::new(size),
+        VoxelGrid::new(size),
+        VoxelGrid::new(size),
+    ];
+    for x in 0..size {
+        for y in 0..size {
+            for z in 0..size {
+                let (voxel_a, voxel_b) = (grid_a.data[x][y][z], grid_b.data[x][y][z]);
+                expected[0].data[x][y][z] = voxel_a.or(voxel_b);
+                expected[1].data[x][y][z] = voxel_a.filter(|_| voxel_b.is_some());
+                expected[2].data[x][y][z] = voxel_a.filter(|_| voxel_b.is_none());
+            }
+        }
+    }
+    let results = [
+        a.union(&b).unwrap(),
+        a.intersection(&b).unwrap(),
+        a.difference(&b).unwrap(),
+    ];
+    for (seed, (result, expected)) in results.iter().zip(expected.iter()).enumerate() {
+        assert_same_raycasts(
+            result,
+            &SparseVoxelOctree::try_from(expected).unwrap(),
+            seed as u64,
+        );
+    }
+
+    // the union with an empty octree copies the other one as it is
+    let empty = SparseVoxelOctree::try_from(&VoxelGrid::new(size)).unwrap();
+    let mut defragmented = a.clone();
+    defragmented.defragment().unwrap();
+    assert_eq!(empty.union(&a).unwrap().node_pool, defragmented.node_pool);
+    assert_eq!(a.difference(&a).unwrap().node_pool, empty.node_pool);
+
+    let small = SparseVoxelOctree::try_from(&VoxelGrid::new(8)).unwrap();
+    assert_eq!(a.union(&small).err(), Some(BuildError::SizeMismatch(16, 8)));
+}
@@ -0,0 +1,102 @@
+use std::collections::HashMap;
+
+use crate::svo::{
+    child_tile, create_node, is_empty, is_leaf, link_child, root_pool, write_tile, BuildError,
+    SVONode, SparseVoxelOctree,
+};
+
+// Rebuilds the node pool so that identical subtrees share a single tile.
+struct DagBuilder<'a> {
+    node_pool: &'a [SVONode],
+    dag_pool: Vec<SVONode>,
+    // tile contents and merged child tile indices -> tile index in dag_pool
+    unique_tiles: HashMap<([SVONode; 8], [usize; 8]), usize>,
+    // tile index in node_pool -> tile index in dag_pool
+    merged_tiles: HashMap<usize, usize>,
+}
+
+impl DagBuilder<'_> {
+    // Merges the subtree below the tile at `tile_idx` bottom-up and returns the
+    // index of the merged tile in the new pool.
+    fn merge_tile(&mut self, tile_idx: usize) -> Result<usize, BuildError> {
+        if let Some(&merged_idx) = self.merged_tiles.get(&tile_idx) {
+            return Ok(merged_idx);
+        }
+        let mut tile = [create_node(); 8];
+        let mut children = [0; 8];
+        for (slot, node) in tile.iter_mut().enumerate() {
+            *node = self.node_pool[tile_idx + slot];
+            if !is_empty(node) && !is_leaf(node) {
+                children[slot] = self.merge_tile(child_tile(self.node_pool, tile_idx + slot))?;
+                // only the flags and colour identify the node, the child tile
+                // is linked when the tile is written
+                node[0] &= 0xc0000000;
+            }
+        }
+        let merged_idx = match self.unique_tiles.get(&(tile, children)) {
+            Some(&merged_idx) => merged_idx,
+            None => {
+                let merged_idx = write_tile(&mut self.dag_pool, &tile, &children)?;
+                self.unique_tiles.insert((tile, children), merged_idx);
+                merged_idx
+            }
+        };
+        self.merged_tiles.insert(tile_idx, merged_idx);
+        Ok(merged_idx)
+    }
+}
+
+impl SparseVoxelOctree {
+    /// Turns the octree into a directed acyclic graph by storing identical
+    /// subtrees (including their colours) only once. The node layout stays the
+    /// same, so the result can be traversed like any other octree. Returns the
+    /// compression ratio, i.e. `size_bytes` before divided by after.
+    pub fn compress_dag(&mut self) -> Result<f32, BuildError> {
+        let size_before = self.size_bytes();
+        let mut builder = DagBuilder {
+            node_pool: &self.node_pool,
+            dag_pool: root_pool(),
+            unique_tiles: HashMap::new(),
+            merged_tiles: HashMap::new(),
+        };
+        let root_tile_idx = builder.merge_tile(child_tile(&self.node_pool, 0))?;
+        let mut dag_pool = builder.dag_pool;
+        dag_pool[0] = self.node_pool[0];
+        link_child(&mut dag_pool, 0, root_tile_idx)?;
+        self.node_pool = dag_pool;
+        Ok(size_before as f32 / self.size_bytes() as f32)
+    }
+}
+
+#[test]
+fn test_compress_dag() {
+    use crate::svo::{assert_same_raycasts, random_voxels};
+    use rand::SeedableRng;
+
+    // a random 8^3 pattern repeated in a 64^3 grid
+    let mut rng = rand::rngs::StdRng::seed_from_u64(7);
+    let pattern = random_voxels(&mut rng, 8, 40);
+    let mut voxels = Vec::new();
+    for x in 0..8 {
+        for y in 0..8 {
+            for z in 0..8 {
+                if (x + y + z) % 3 != 0 {
+                    let offset = na::Vector3::new(x, y, z) * 8;
+                    voxels.extend(pattern.iter().map(|&(p, color)| (p + offset, color)));
+                }
+            }
+        }
+    }
+    let svo = SparseVoxelOctree::from_voxels(64, voxels).unwrap();
+    let mut dag = svo.clone();
+
+    let ratio = dag.compress_dag().unwrap();
+    assert_eq!(ratio, svo.size_bytes() as f32 / dag.size_bytes() as f32);
+    assert!(ratio > 10.0);
+    assert_same_raycasts(&svo, &dag, 7);
+
+    // compressing twice does not change anything
+    let node_pool = dag.node_pool.clone();
+    assert_eq!(dag.compress_dag().unwrap(), 1.0);
+    assert_eq!(node_pool, dag.node_pool);
+}
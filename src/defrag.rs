@@ -0,0 +1,77 @@
+use std::collections::HashMap;
+
+use crate::svo::{child_tile, copy_subtree, link_child, root_pool, BuildError, SparseVoxelOctree};
+
+impl SparseVoxelOctree {
+    // Compacts the node pool like `defragment`. The tiles in `extra_tiles`
+    // are kept alive as well and replaced by their new indices.
+    pub(crate) fn defragment_with(
+        &mut self,
+        extra_tiles: &mut [usize],
+    ) -> Result<usize, BuildError> {
+        let size_before = self.size_bytes();
+        let mut node_pool = root_pool();
+        let mut copied = HashMap::new();
+        let root_tile_idx = copy_subtree(
+            &self.node_pool,
+            child_tile(&self.node_pool, 0),
+            &mut node_pool,
+            &mut copied,
+        )?;
+        for tile_idx in extra_tiles.iter_mut() {
+            *tile_idx = copy_subtree(&self.node_pool, *tile_idx, &mut node_pool, &mut copied)?;
+        }
+        node_pool[0] = self.node_pool[0];
+        link_child(&mut node_pool, 0, root_tile_idx)?;
+        self.node_pool = node_pool;
+        Ok(size_before - self.size_bytes())
+    }
+
+    /// Removes the tiles that are no longer reachable from the root, e.g.
+    /// after editing, and stores the remaining ones depth-first, each subtree
+    /// in one contiguous block after its children. Tiles shared by a DAG stay
+    /// shared. Returns the number of bytes reclaimed.
+    pub fn defragment(&mut self) -> Result<usize, BuildError> {
+        self.defragment_with(&mut [])
+    }
+}
+
+#[test]
+fn test_defragment() {
+    use crate::svo::{assert_same_raycasts, random_voxels};
+    use na::Point3;
+    use rand::SeedableRng;
+
+    let mut rng = rand::rngs::StdRng::seed_from_u64(12);
+    let mut voxels: HashMap<_, _> = random_voxels(&mut rng, 32, 500).into_iter().collect();
+    let mut svo = SparseVoxelOctree::from_voxels(32, voxels.clone()).unwrap();
+    let node_pool = svo.node_pool.clone();
+    assert_eq!(svo.defragment().unwrap(), 0);
+    assert_eq!(svo.node_pool, node_pool);
+
+    for (i, (p, color)) in random_voxels(&mut rng, 32, 200).into_iter().enumerate() {
+        if i % 3 == 0 {
+            voxels.remove(&p);
+            svo.clear_voxel(p).unwrap();
+        } else {
+            voxels.insert(p, color);
+            svo.set_voxel(p, color).unwrap();
+        }
+    }
+    let size_before = svo.size_bytes();
+    let reclaimed = svo.defragment().unwrap();
+    assert_eq!(reclaimed, size_before - svo.size_bytes());
+    // the layout is the same as if the octree had been built from scratch
+    let rebuilt = SparseVoxelOctree::from_voxels(32, voxels).unwrap();
+    assert_eq!(svo.node_pool, rebuilt.node_pool);
+
+    // edits of a DAG leave garbage too, but shared tiles are kept shared
+    let mut dag = rebuilt.clone();
+    dag.compress_dag().unwrap();
+    let dag_size = dag.size_bytes();
+    dag.set_voxel(Point3::new(1, 2, 3), 0).unwrap();
+    dag.clear_voxel(Point3::new(1, 2, 3)).unwrap();
+    assert!(dag.defragment().unwrap() > 0);
+    assert!(dag.size_bytes() <= dag_size);
+    assert_same_raycasts(&rebuilt, &dag, 12);
+}
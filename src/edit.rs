@@ -0,0 +1,213 @@
+use na::{base::Vector3, geometry::Point3};
+
+use crate::svo::{
+    child_tile, create_node, is_empty, link_child, node_children, set_node, write_children,
+    BuildError, NodeRef, SparseVoxelOctree,
+};
+use crate::traverse::child_offset;
+
+// Sets the voxels in `min..max` to `color`, or clears them if it is `None`.
+struct BoxEdit {
+    min: Point3<usize>,
+    max: Point3<usize>,
+    color: Option<u32>,
+}
+
+impl BoxEdit {
+    fn intersects(&self, min: Point3<usize>, size: usize) -> bool {
+        (0..3).all(|i| min[i] < self.max[i] && self.min[i] < min[i] + size)
+    }
+
+    fn covers(&self, min: Point3<usize>, size: usize) -> bool {
+        (0..3).all(|i| self.min[i] <= min[i] && min[i] + size <= self.max[i])
+    }
+
+    // Node of a cube that lies completely inside of the box, which becomes a
+    // single leaf no matter how large it is.
+    fn node(&self) -> NodeRef {
+        let mut node = create_node();
+        if let Some(color) = self.color {
+            set_node(&mut node, false, true, 0, color);
+        }
+        (node, 0)
+    }
+}
+
+impl SparseVoxelOctree {
+    // Applies the edit to the children of `node`, which covers the cube with
+    // the given size at `min`. Returns `None` if none of them changed.
+    fn edit_children(
+        &mut self,
+        node: NodeRef,
+        min: Point3<usize>,
+        size: usize,
+        edit: &BoxEdit,
+    ) -> Result<Option<[NodeRef; 8]>, BuildError> {
+        let half_size = size / 2;
+        let mut children = node_children(&self.node_pool, node);
+        let mut changed = false;
+        for digit in 0..8 {
+            let child = &mut children[7 ^ digit];
+            let child_min = min + child_offset(digit, half_size);
+            let edited = self.edit_node(*child, child_min, half_size, edit)?;
+            changed |= edited != *child;
+            *child = edited;
+        }
+        Ok(if changed { Some(children) } else { None })
+    }
+
+    // Returns the edited node. Changed tiles are appended to the pool instead
+    // of being overwritten, as they may be shared after `compress_dag`.
+    fn edit_node(
+        &mut self,
+        node: NodeRef,
+        min: Point3<usize>,
+        size: usize,
+        edit: &BoxEdit,
+    ) -> Result<NodeRef, BuildError> {
+        if !edit.intersects(min, size) {
+            return Ok(node);
+        }
+        if edit.covers(min, size) {
+            return Ok(edit.node());
+        }
+        match self.edit_children(node, min, size, edit)? {
+            None => Ok(node),
+            Some(children) if children.iter().all(|(child, _)| is_empty(child)) => {
+                Ok((create_node(), 0))
+            }
+            Some(children) => write_children(&mut self.node_pool, &children),
+        }
+    }
+
+    fn edit_box(&mut self, edit: BoxEdit) -> Result<(), BuildError> {
+        if (0..3).any(|i| edit.min[i] >= edit.max[i]) {
+            return Ok(());
+        }
+        if (0..3).any(|i| edit.max[i] > self.size) {
+            return Err(BuildError::OutOfBounds(edit.max - Vector3::repeat(1)));
+        }
+        // The root always keeps a tile, even if the whole grid ends up empty
+        // or filled.
+        let root = (self.node_pool[0], child_tile(&self.node_pool, 0));
+        if let Some(children) = self.edit_children(root, Point3::origin(), self.size, &edit)? {
+            let (root, root_tile_idx) = write_children(&mut self.node_pool, &children)?;
+            self.node_pool[0] = root;
+            link_child(&mut self.node_pool, 0, root_tile_idx)?;
+        }
+        Ok(())
+    }
+
+    /// Sets the voxel at `p` to the given RGBA colour. Nodes on the way are
+    /// copied, so the old tiles stay in the node pool until it is compacted.
+    pub fn set_voxel(&mut self, p: Point3<usize>, color: u32) -> Result<(), BuildError> {
+        self.fill_box(p, p + Vector3::repeat(1), color)
+    }
+
+    /// Removes the voxel at `p`. Subtrees that become empty are removed too.
+    pub fn clear_voxel(&mut self, p: Point3<usize>) -> Result<(), BuildError> {
+        self.clear_box(p, p + Vector3::repeat(1))
+    }
+
+    /// Sets all voxels in `min..max` to the given RGBA colour. Cubes that lie
+    /// completely inside of the box become a single leaf.
+    pub fn fill_box(
+        &mut self,
+        min: Point3<usize>,
+        max: Point3<usize>,
+        color: u32,
+    ) -> Result<(), BuildError> {
+        self.edit_box(BoxEdit {
+            min,
+            max,
+            color: Some(color),
+        })
+    }
+
+    /// Removes all voxels in `min..max`.
+    pub fn clear_box(&mut self, min: Point3<usize>, max: Point3<usize>) -> Result<(), BuildError> {
+        self.edit_box(BoxEdit {
+            min,
+            max,
+            color: None,
+        })
+    }
+}
+
+#[test]
+fn test_editing() {
+    use crate::svo::assert_same_raycasts;
+    use crate::voxel_grid::VoxelGrid;
+    use rand::{Rng, SeedableRng};
+    use std::convert::TryFrom;
+
+    let mut rng = rand::rngs::StdRng::seed_from_u64(11);
+    let size = 16;
+    let mut voxel_grid = VoxelGrid::new(size);
+    let mut svo = SparseVoxelOctree::try_from(&voxel_grid).unwrap();
+    for step in 0..60u64 {
+        let color = if rng.gen_bool(0.6) {
+            Some(rng.gen())
+        } else {
+            None
+        };
+        let min = Point3::new(
+            rng.gen_range(0..size),
+            rng.gen_range(0..size),
+            rng.gen_range(0..size),
+        );
+        let max = if rng.gen_bool(0.5) {
+            min + Vector3::repeat(1)
+        } else {
+            Point3::new(
+                rng.gen_range(min.x + 1..=size),
+                rng.gen_range(min.y + 1..=size),
+                rng.gen_range(min.z + 1..=size),
+            )
+        };
+        for x in min.x..max.x {
+            for y in min.y..max.y {
+                for z in min.z..max.z {
+                    voxel_grid.data[x][y][z] = color;
+                }
+            }
+        }
+        match (color, max - min == Vector3::repeat(1)) {
+            (Some(color), true) => svo.set_voxel(min, color).unwrap(),
+            (None, true) => svo.clear_voxel(min).unwrap(),
+            (Some(color), false) => svo.fill_box(min, max, color).unwrap(),
+            (None, false) => svo.clear_box(min, max).unwrap(),
+        }
+        if step % 10 == 9 {
+            assert_same_raycasts(
+                &svo,
+                &SparseVoxelOctree::try_from(&voxel_grid).unwrap(),
+                step,
+            );
+            // editing a DAG must not change the subtrees that share tiles
+            let mut dag = svo.clone();
+            dag.compress_dag().unwrap();
+            dag.set_voxel(Point3::new(0, 0, 0), 0xFFFFFFFF).unwrap();
+            let mut dag_grid = VoxelGrid::new(size);
+            dag_grid.data = voxel_grid.data.clone();
+            dag_grid.data[0][0][0] = Some(0xFFFFFFFF);
+            assert_same_raycasts(&dag, &SparseVoxelOctree::try_from(&dag_grid).unwrap(), step);
+        }
+    }
+
+    // clearing everything collapses the octree to an empty root tile
+    let pool_len = svo.node_pool.len();
+    svo.clear_box(Point3::origin(), Point3::new(size, size, size))
+        .unwrap();
+    let root_tile_idx = child_tile(&svo.node_pool, 0);
+    assert_eq!(root_tile_idx, pool_len);
+    assert!((0..8).all(|slot| is_empty(&svo.node_pool[root_tile_idx + slot])));
+    // edits that do not change anything do not allocate
+    svo.clear_voxel(Point3::new(3, 4, 5)).unwrap();
+    assert_eq!(svo.node_pool.len(), pool_len + 8);
+
+    assert_eq!(
+        svo.set_voxel(Point3::new(0, 16, 0), 0),
+        Err(BuildError::OutOfBounds(Point3::new(0, 16, 0)))
+    );
+}
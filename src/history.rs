@@ -0,0 +1,301 @@
+use na::geometry::Point3;
+
+use crate::raycast::{RayQuery, RaycastHit, Raycastable};
+use crate::svo::{
+    child_tile, link_child, raycast_octree, BuildError, SparseVoxelOctree, Traversable,
+};
+
+/// Octree with undo history. Edits copy the tiles on the path to the root and
+/// leave the old ones in place, so every version shares all unchanged subtrees
+/// with the others. The root node of each version is kept in the node pool.
+pub struct VersionedOctree {
+    svo: SparseVoxelOctree,
+    // index of the root node of each version
+    versions: Vec<usize>,
+    current: usize,
+}
+
+// Appends a copy of the current root of `svo` and returns its index.
+fn push_root(svo: &mut SparseVoxelOctree) -> Result<usize, BuildError> {
+    let root_idx = svo.node_pool.len();
+    let root_tile_idx = child_tile(&svo.node_pool, 0);
+    svo.node_pool.push(svo.node_pool[0]);
+    link_child(&mut svo.node_pool, root_idx, root_tile_idx)?;
+    Ok(root_idx)
+}
+
+impl VersionedOctree {
+    pub fn new(mut svo: SparseVoxelOctree) -> Result<VersionedOctree, BuildError> {
+        let root_idx = push_root(&mut svo)?;
+        Ok(VersionedOctree {
+            svo,
+            versions: vec![root_idx],
+            current: 0,
+        })
+    }
+
+    /// The octree of the current version.
+    pub fn octree(&self) -> &SparseVoxelOctree {
+        &self.svo
+    }
+
+    pub fn current_version(&self) -> usize {
+        self.current
+    }
+
+    pub fn version_count(&self) -> usize {
+        self.versions.len()
+    }
+
+    /// Applies `edit` to the octree, e.g. `|editor| editor.set_voxel(p, color)`,
+    /// and stores the result as a new version after the current one. Versions
+    /// that were undone before are dropped. If the edit fails, the current
+    /// version is restored.
+    pub fn edit<F>(&mut self, edit: F) -> Result<(), BuildError>
+    where
+        F: FnOnce(&mut OctreeEditor) -> Result<(), BuildError>,
+    {
+        if let Err(err) = edit(&mut OctreeEditor { svo: &mut self.svo }) {
+            self.restore_root(self.current)?;
+            return Err(err);
+        }
+        let root_idx = push_root(&mut self.svo)?;
+        self.versions.truncate(self.current + 1);
+        self.versions.push(root_idx);
+        self.current += 1;
+        Ok(())
+    }
+
+    // Copies the root node of the given version to the root of the octree.
+    fn restore_root(&mut self, version: usize) -> Result<(), BuildError> {
+        let root_idx = self.versions[version];
+        let root_tile_idx = child_tile(&self.svo.node_pool, root_idx);
+        self.svo.node_pool[0] = self.svo.node_pool[root_idx];
+        link_child(&mut self.svo.node_pool, 0, root_tile_idx)
+    }
+
+    /// Makes the given version the current one. Returns false if it does not
+    /// exist.
+    pub fn checkout(&mut self, version: usize) -> Result<bool, BuildError> {
+        if version >= self.versions.len() {
+            return Ok(false);
+        }
+        self.restore_root(version)?;
+        self.current = version;
+        Ok(true)
+    }
+
+    /// Goes back to the previous version. Returns false if there is none.
+    pub fn undo(&mut self) -> Result<bool, BuildError> {
+        if self.current == 0 {
+            return Ok(false);
+        }
+        self.checkout(self.current - 1)?;
+        Ok(true)
+    }
+
+    /// Goes forward to the version that was undone last. Returns false if
+    /// there is none.
+    pub fn redo(&mut self) -> Result<bool, BuildError> {
+        if self.current + 1 == self.versions.len() {
+            return Ok(false);
+        }
+        self.checkout(self.current + 1)?;
+        Ok(true)
+    }
+
+    /// Read access to any version without checking it out, e.g. to raycast it.
+    /// Returns `None` if it does not exist.
+    pub fn version(&self, version: usize) -> Option<OctreeVersion<'_>> {
+        Some(OctreeVersion {
+            svo: &self.svo,
+            root: *self.versions.get(version)?,
+        })
+    }
+
+    /// Removes the tiles that no version uses anymore. Returns the number of
+    /// bytes reclaimed.
+    pub fn defragment(&mut self) -> Result<usize, BuildError> {
+        let size_before = self.svo.size_bytes();
+        let roots: Vec<_> = self
+            .versions
+            .iter()
+            .map(|&idx| self.svo.node_pool[idx])
+            .collect();
+        let mut tiles: Vec<_> = self
+            .versions
+            .iter()
+            .map(|&idx| child_tile(&self.svo.node_pool, idx))
+            .collect();
+        self.svo.defragment_with(&mut tiles)?;
+        for (version, (root, tile_idx)) in roots.into_iter().zip(tiles).enumerate() {
+            let root_idx = self.svo.node_pool.len();
+            self.svo.node_pool.push(root);
+            link_child(&mut self.svo.node_pool, root_idx, tile_idx)?;
+            self.versions[version] = root_idx;
+        }
+        Ok(size_before - self.svo.size_bytes())
+    }
+}
+
+impl Raycastable for VersionedOctree {
+    fn raycast_query(&self, query: &RayQuery) -> Option<RaycastHit> {
+        self.svo.raycast_query(query)
+    }
+}
+
+/// Voxel edits of a `VersionedOctree`. Only edits that copy the path to the
+/// root are offered, as anything that moves tiles would break the other
+/// versions.
+pub struct OctreeEditor<'a> {
+    svo: &'a mut SparseVoxelOctree,
+}
+
+impl OctreeEditor<'_> {
+    pub fn set_voxel(&mut self, p: Point3<usize>, color: u32) -> Result<(), BuildError> {
+        self.svo.set_voxel(p, color)
+    }
+
+    pub fn clear_voxel(&mut self, p: Point3<usize>) -> Result<(), BuildError> {
+        self.svo.clear_voxel(p)
+    }
+
+    pub fn fill_box(
+        &mut self,
+        min: Point3<usize>,
+        max: Point3<usize>,
+        color: u32,
+    ) -> Result<(), BuildError> {
+        self.svo.fill_box(min, max, color)
+    }
+
+    pub fn clear_box(&mut self, min: Point3<usize>, max: Point3<usize>) -> Result<(), BuildError> {
+        self.svo.clear_box(min, max)
+    }
+}
+
+/// A version of a `VersionedOctree`.
+pub struct OctreeVersion<'a> {
+    svo: &'a SparseVoxelOctree,
+    root: usize,
+}
+
+impl Traversable for OctreeVersion<'_> {
+    type Ref = usize;
+
+    fn root(&self) -> usize {
+        self.root
+    }
+
+    fn child(&self, node: usize, slot: u32) -> Option<usize> {
+        self.svo.child(node, slot)
+    }
+
+    fn is_leaf(&self, node: usize) -> bool {
+        Traversable::is_leaf(self.svo, node)
+    }
+
+    fn color(&self, node: usize) -> u32 {
+        self.svo.color(node)
+    }
+
+    fn index(&self, node: usize) -> usize {
+        node
+    }
+
+    fn size(&self) -> usize {
+        self.svo.size
+    }
+}
+
+impl Raycastable for OctreeVersion<'_> {
+    fn raycast_query(&self, query: &RayQuery) -> Option<RaycastHit> {
+        raycast_octree(self, query)
+    }
+}
+
+#[test]
+fn test_history() {
+    use crate::svo::{assert_same_raycasts, random_grid, random_voxels};
+    use crate::voxel_grid::VoxelGrid;
+    use na::base::Vector3;
+    use rand::SeedableRng;
+    use std::convert::TryFrom;
+
+    let size = 32;
+    let mut rng = rand::rngs::StdRng::seed_from_u64(14);
+    let mut voxel_grid = random_grid(&mut rng, size, 0.06);
+    let mut history =
+        VersionedOctree::new(SparseVoxelOctree::try_from(&voxel_grid).unwrap()).unwrap();
+    let initial_size = history.octree().size_bytes();
+
+    let mut grids = vec![voxel_grid.data.clone()];
+    for (i, (p, color)) in random_voxels(&mut rng, size, 50).into_iter().enumerate() {
+        if i % 2 == 0 {
+            history.edit(|editor| editor.clear_voxel(p)).unwrap();
+            voxel_grid.data[p.x][p.y][p.z] = None;
+        } else {
+            history.edit(|editor| editor.set_voxel(p, color)).unwrap();
+            voxel_grid.data[p.x][p.y][p.z] = Some(color);
+        }
+        grids.push(voxel_grid.data.clone());
+    }
+    // each edit only copies the path to the root
+    let levels = size.trailing_zeros() as usize;
+    assert!(history.octree().size_bytes() <= initial_size + 50 * (8 * levels + 2) * 8);
+
+    let expected = |version: usize| {
+        let mut voxel_grid = VoxelGrid::new(size);
+        voxel_grid.data = grids[version].clone();
+        SparseVoxelOctree::try_from(&voxel_grid).unwrap()
+    };
+    for version in (0..51).step_by(10) {
+        assert_same_raycasts(
+            &history.version(version).unwrap(),
+            &expected(version),
+            version as u64,
+        );
+    }
+
+    assert!(history.undo().unwrap());
+    assert!(history.undo().unwrap());
+    assert_eq!(history.current_version(), 48);
+    assert_same_raycasts(&history, &expected(48), 48);
+    assert!(history.redo().unwrap());
+    assert_same_raycasts(&history, &expected(49), 49);
+
+    assert!(history.checkout(0).unwrap());
+    assert!(!history.undo().unwrap());
+    assert_same_raycasts(&history, &expected(0), 0);
+    assert!(history.checkout(50).unwrap());
+    assert!(!history.redo().unwrap());
+    // unknown versions are left alone
+    assert!(!history.checkout(51).unwrap());
+    assert!(history.version(51).is_none());
+    assert_eq!(history.current_version(), 50);
+
+    // an edit after undoing drops the undone versions
+    assert!(history.checkout(10).unwrap());
+    history
+        .edit(|editor| editor.clear_box(Point3::origin(), Point3::new(size, size, size)))
+        .unwrap();
+    assert_eq!(history.version_count(), 12);
+    // a failing edit leaves the current version untouched
+    assert_eq!(
+        history.edit(|editor| {
+            editor.set_voxel(Point3::new(1, 2, 3), 1)?;
+            editor.set_voxel(Point3::new(size, 0, 0), 1)
+        }),
+        Err(BuildError::OutOfBounds(Point3::new(size, 0, 0)))
+    );
+    assert_eq!(history.version_count(), 12);
+    assert!(history
+        .octree()
+        .raycast(Point3::new(-1.0, 0.5, 0.5), Vector3::x())
+        .is_none());
+
+    assert!(history.defragment().unwrap() > 0);
+    assert_same_raycasts(&history.version(10).unwrap(), &expected(10), 10);
+    history.undo().unwrap();
+    assert_same_raycasts(&history, &expected(10), 10);
+}
@@ -4,11 +4,23 @@ use std::f32;
 
 mod bvh;
 mod camera;
+mod compact_svo;
+mod csg;
+mod dag;
+mod defrag;
+mod edit;
+mod history;
 mod morton;
+mod nearest;
+mod query;
 mod raycast;
 mod raytracer;
+mod sdf;
 mod shader;
 mod svo;
+mod sweep;
+mod symmetric_dag;
+mod traverse;
 mod ui;
 mod util;
 mod voxel_grid;
@@ -19,7 +31,6 @@ use raytracer::Raytracer;
 use svo::SparseVoxelOctree;
 use ui::ImguiContext;
 use util::clamp;
-use voxel_grid::VoxelGrid;
 use window::{RenderContext, WindowContext};
 
 use na::base::{Matrix4, Vector4};
@@ -36,10 +47,7 @@ fn main() {
     }
     let model_path = &args[1];
     // build svo
-    let svo = {
-        let voxel_grid = VoxelGrid::from_csv(model_path.to_string()).unwrap();
-        SparseVoxelOctree::from(&voxel_grid)
-    };
+    let svo = SparseVoxelOctree::from_csv(model_path.to_string()).unwrap();
 
     // render
     let mut camera = Camera::new();
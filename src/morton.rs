@@ -8,7 +8,7 @@ pub fn decode_3d(x: u64) -> (u64, u64, u64) {
 
 // "Insert" two 0 bits after each of the 21 low bits of x
 fn bloat_2(mut x: u64) -> u64 {
-    x = x & 0x1fffff;
+    x &= 0x1fffff;
     x = (x | (x << 32)) & 0x1f00000000ffff;
     x = (x | (x << 16)) & 0x1f0000ff0000ff;
     x = (x | (x << 8)) & 0x100f00f00f00f00f;
@@ -18,7 +18,7 @@ fn bloat_2(mut x: u64) -> u64 {
 
 // reverse bloat_2
 fn shrink_2(mut x: u64) -> u64 {
-    x = x & 0x1249249249249249;
+    x &= 0x1249249249249249;
     x = (x ^ (x >> 2)) & 0x10c30c30c30c30c3;
     x = (x ^ (x >> 4)) & 0x100f00f00f00f00f;
     x = (x ^ (x >> 8)) & 0x1f0000ff0000ff;
@@ -0,0 +1,135 @@
+use na::{base::Vector3, geometry::Point3};
+
+use crate::svo::{is_empty, is_leaf, SparseVoxelOctree};
+use crate::traverse::{leaf_voxel, NodeInfo};
+
+// Distance from `p` to the box with the given corners.
+pub(crate) fn box_distance(p: Point3<f32>, min: Point3<f32>, max: Point3<f32>) -> f32 {
+    let mut squared = 0.0;
+    for i in 0..3 {
+        let outside = (min[i] - p[i]).max(p[i] - max[i]).max(0.0);
+        squared += outside * outside;
+    }
+    squared.sqrt()
+}
+
+/// Result of `SparseVoxelOctree::nearest_voxel`.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct NearestVoxel {
+    pub voxel: Point3<usize>,
+    pub distance: f32,
+    pub color: u32,
+}
+
+impl SparseVoxelOctree {
+    /// Returns the filled voxel closest to `p` if it is at most `max_distance`
+    /// away. The distance is measured to the closest point of the voxel, so
+    /// it is zero inside of it.
+    pub fn nearest_voxel(&self, p: Point3<f32>, max_distance: f32) -> Option<NearestVoxel> {
+        let (leaf, distance) = self.nearest_node(p, max_distance, true)?;
+        Some(NearestVoxel {
+            voxel: leaf_voxel(p, leaf.min, leaf.size, self.size),
+            distance,
+            color: leaf.color,
+        })
+    }
+
+    // World space corners of the cube of a node.
+    pub(crate) fn node_bounds(
+        &self,
+        min: Point3<usize>,
+        size: usize,
+    ) -> (Point3<f32>, Point3<f32>) {
+        let scale = 1.0 / self.size as f32;
+        let min = Point3::from(min.coords.map(|v| v as f32 * scale));
+        (min, min + Vector3::repeat(size as f32 * scale))
+    }
+
+    // Best-first search for the node closest to `p` that is a leaf if `filled`
+    // is set, or empty otherwise. Nodes are visited in order of their
+    // distance, so whole subtrees that are further away than the best match
+    // or `max_distance` are never entered.
+    pub(crate) fn nearest_node(
+        &self,
+        p: Point3<f32>,
+        max_distance: f32,
+        filled: bool,
+    ) -> Option<(NodeInfo, f32)> {
+        let mut nearest = None;
+        self.best_first(
+            |node, min, size| {
+                if (filled && is_empty(node)) || (!filled && is_leaf(node)) {
+                    return None;
+                }
+                let (lower, upper) = self.node_bounds(min, size);
+                Some(box_distance(p, lower, upper))
+            },
+            |node, min, depth, distance| {
+                if distance > max_distance {
+                    return false;
+                }
+                if (filled && is_leaf(node)) || (!filled && is_empty(node)) {
+                    let info = NodeInfo {
+                        min,
+                        size: self.size >> depth,
+                        depth,
+                        color: node[1],
+                        is_leaf: is_leaf(node),
+                    };
+                    nearest = Some((info, distance));
+                    return false;
+                }
+                true
+            },
+        );
+        nearest
+    }
+}
+
+#[test]
+fn test_nearest_voxel() {
+    use crate::svo::random_voxels;
+    use rand::{Rng, SeedableRng};
+
+    let size = 16;
+    let mut rng = rand::rngs::StdRng::seed_from_u64(19);
+    let voxels = random_voxels(&mut rng, size, 40);
+    let mut svo = SparseVoxelOctree::from_voxels(size, voxels.clone()).unwrap();
+    let voxel_distance = |p: Point3<f32>, voxel: Point3<usize>| {
+        let (min, max) = svo.node_bounds(voxel, 1);
+        box_distance(p, min, max)
+    };
+    for _ in 0..500 {
+        let p = Point3::new(
+            rng.gen_range(-0.5..1.5),
+            rng.gen_range(-0.5..1.5),
+            rng.gen_range(-0.5..1.5),
+        );
+        let expected = voxels
+            .iter()
+            .map(|&(voxel, _)| voxel_distance(p, voxel))
+            .fold(f32::MAX, f32::min);
+        let nearest = svo.nearest_voxel(p, 2.0).unwrap();
+        assert!((nearest.distance - expected).abs() < 1e-6);
+        assert!((voxel_distance(p, nearest.voxel) - expected).abs() < 1e-6);
+        let color = voxels
+            .iter()
+            .find(|&&(voxel, _)| voxel == nearest.voxel)
+            .unwrap()
+            .1;
+        assert_eq!(nearest.color, color);
+        if expected > 0.1 {
+            assert_eq!(svo.nearest_voxel(p, 0.1), None);
+        }
+    }
+
+    // the closest voxel of a larger leaf is returned
+    svo.fill_box(Point3::new(8, 8, 8), Point3::new(16, 16, 16), 3)
+        .unwrap();
+    let nearest = svo
+        .nearest_voxel(Point3::new(2.0, 0.75, 0.6), 10.0)
+        .unwrap();
+    assert_eq!(nearest.voxel, Point3::new(15, 12, 9));
+    assert_eq!(nearest.color, 3);
+    assert!((nearest.distance - 1.0).abs() < 1e-6);
+}
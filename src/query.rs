@@ -0,0 +1,143 @@
+use na::{base::Vector3, geometry::Point3};
+
+use crate::svo::{child_tile, is_empty, is_leaf, SparseVoxelOctree};
+use crate::traverse::child_offset;
+
+// Number of voxels of the cube with the given size at `cube_min` that lie in
+// `min..max`, per axis.
+fn overlap(
+    cube_min: Point3<usize>,
+    size: usize,
+    min: Point3<usize>,
+    max: Point3<usize>,
+) -> Vector3<usize> {
+    Vector3::from_fn(|i, _| {
+        let start = cube_min[i].max(min[i]);
+        let end = (cube_min[i] + size).min(max[i]);
+        end.saturating_sub(start)
+    })
+}
+
+impl SparseVoxelOctree {
+    /// Returns true if the voxel at (x, y, z) is filled.
+    pub fn contains(&self, x: usize, y: usize, z: usize) -> bool {
+        if x >= self.size || y >= self.size || z >= self.size {
+            return false;
+        }
+        let mut node_idx = 0;
+        let mut half_size = self.size / 2;
+        loop {
+            if is_leaf(&self.node_pool[node_idx]) {
+                return true;
+            }
+            let digit = (x & half_size != 0) as usize
+                | ((y & half_size != 0) as usize) << 1
+                | ((z & half_size != 0) as usize) << 2;
+            node_idx = child_tile(&self.node_pool, node_idx) + (7 ^ digit);
+            if is_empty(&self.node_pool[node_idx]) {
+                return false;
+            }
+            half_size /= 2;
+        }
+    }
+
+    /// Returns true if any voxel in `min..max` is filled.
+    pub fn any_in_box(&self, min: Point3<usize>, max: Point3<usize>) -> bool {
+        self.count_node(0, Point3::origin(), self.size, min, max, true) > 0
+    }
+
+    /// Returns the number of filled voxels in `min..max`.
+    pub fn count_in_box(&self, min: Point3<usize>, max: Point3<usize>) -> usize {
+        self.count_node(0, Point3::origin(), self.size, min, max, false)
+    }
+
+    // Counts the filled voxels in `min..max` below the filled node at
+    // `node_idx`, which covers the cube with the given size at `cube_min`. If
+    // `any` is set, it stops at the first one.
+    fn count_node(
+        &self,
+        node_idx: usize,
+        cube_min: Point3<usize>,
+        size: usize,
+        min: Point3<usize>,
+        max: Point3<usize>,
+        any: bool,
+    ) -> usize {
+        let overlap = overlap(cube_min, size, min, max);
+        if overlap.iter().any(|&n| n == 0) {
+            return 0;
+        }
+        if is_leaf(&self.node_pool[node_idx]) {
+            return overlap.x * overlap.y * overlap.z;
+        }
+        let half_size = size / 2;
+        let tile_idx = child_tile(&self.node_pool, node_idx);
+        let mut count = 0;
+        for digit in 0..8 {
+            let child_idx = tile_idx + (7 ^ digit);
+            if is_empty(&self.node_pool[child_idx]) {
+                continue;
+            }
+            let child_min = cube_min + child_offset(digit, half_size);
+            count += self.count_node(child_idx, child_min, half_size, min, max, any);
+            if any && count > 0 {
+                break;
+            }
+        }
+        count
+    }
+}
+
+#[test]
+fn test_occupancy_queries() {
+    use crate::svo::random_grid;
+    use rand::{Rng, SeedableRng};
+    use std::convert::TryFrom;
+
+    let size = 16;
+    let mut rng = rand::rngs::StdRng::seed_from_u64(17);
+    let mut voxel_grid = random_grid(&mut rng, size, 0.05);
+    let mut svo = SparseVoxelOctree::try_from(&voxel_grid).unwrap();
+    // a coarse leaf counts as all of its voxels
+    svo.fill_box(Point3::new(8, 8, 8), Point3::new(16, 16, 16), 1)
+        .unwrap();
+    for x in 8..16 {
+        for y in 8..16 {
+            for z in 8..16 {
+                voxel_grid.data[x][y][z] = Some(1);
+            }
+        }
+    }
+
+    for x in 0..size {
+        for y in 0..size {
+            for z in 0..size {
+                assert_eq!(svo.contains(x, y, z), voxel_grid.data[x][y][z].is_some());
+            }
+        }
+    }
+    assert!(!svo.contains(16, 0, 0));
+
+    for _ in 0..200 {
+        let min = Point3::new(
+            rng.gen_range(0..size),
+            rng.gen_range(0..size),
+            rng.gen_range(0..size),
+        );
+        let max = Point3::new(
+            rng.gen_range(min.x..=size + 2),
+            rng.gen_range(min.y..=size),
+            rng.gen_range(min.z..=size),
+        );
+        let mut expected = 0;
+        for x in min.x..max.x.min(size) {
+            for y in min.y..max.y {
+                for z in min.z..max.z {
+                    expected += voxel_grid.data[x][y][z].is_some() as usize;
+                }
+            }
+        }
+        assert_eq!(svo.count_in_box(min, max), expected);
+        assert_eq!(svo.any_in_box(min, max), expected > 0);
+    }
+}
@@ -4,8 +4,125 @@ pub struct RaycastHit {
     pub color: u32,
     pub normal: Vector3<f32>,
     pub pos: Point3<f32>,
+    /// Distance to the hit in multiples of the ray direction, so that
+    /// `pos = origin + t * dir`.
+    pub t: f32,
+    /// The voxel that was hit. If the traversal stopped at a node above the
+    /// lowest level, this is the voxel of its cube at `pos`.
+    pub voxel: Point3<usize>,
+    /// Level of the node that was hit, where the root is at depth 0.
+    pub depth: usize,
+    /// Index of the node that was hit in the node pool. For a `VoxelGrid`,
+    /// the index of the voxel with z changing fastest.
+    pub node: usize,
+}
+
+/// Size of the area covered by a ray, which grows linearly with the distance
+/// from the origin. A traversal may stop at the first voxel that is smaller
+/// than the footprint and return its filtered attributes instead.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct RayFootprint {
+    /// Growth of the footprint per unit of distance, e.g. the size of a pixel
+    /// at distance 1.
+    pub size_coef: f32,
+    /// Footprint at the ray origin.
+    pub size_bias: f32,
+}
+
+impl RayFootprint {
+    /// Footprint of an infinitely thin ray, which always reaches the leaves.
+    pub fn zero() -> RayFootprint {
+        RayFootprint {
+            size_coef: 0.0,
+            size_bias: 0.0,
+        }
+    }
+
+    /// Footprint of a cone with the given apex angle in radians.
+    pub fn from_cone_angle(angle: f32) -> RayFootprint {
+        RayFootprint {
+            size_coef: 2.0 * (angle * 0.5).tan(),
+            size_bias: 0.0,
+        }
+    }
+}
+
+/// A ray and the interval of distances `t_min..=t_max` in which it can hit
+/// something. Distances are in multiples of `dir`. Voxels that contain the
+/// point at `t_min` are hit there, like voxels that contain the origin.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct RayQuery {
+    pub origin: Point3<f32>,
+    pub dir: Vector3<f32>,
+    pub t_min: f32,
+    pub t_max: f32,
+    pub footprint: RayFootprint,
+    /// Return the first hit that is found instead of the closest one. The
+    /// octrees and grids are traversed front to back, so for them the first
+    /// hit is the closest anyway, but a collection of them can stop early.
+    pub any_hit: bool,
+}
+
+impl RayQuery {
+    /// Closest hit of a thin ray from the origin to infinity.
+    pub fn new(origin: Point3<f32>, dir: Vector3<f32>) -> RayQuery {
+        RayQuery {
+            origin,
+            dir,
+            t_min: 0.0,
+            t_max: f32::INFINITY,
+            footprint: RayFootprint::zero(),
+            any_hit: false,
+        }
+    }
 }
 
 pub trait Raycastable {
-    fn raycast(&self, origin: Point3<f32>, dir: Vector3<f32>) -> Option<RaycastHit>;
+    fn raycast_query(&self, query: &RayQuery) -> Option<RaycastHit>;
+
+    fn raycast_footprint(
+        &self,
+        origin: Point3<f32>,
+        dir: Vector3<f32>,
+        footprint: &RayFootprint,
+    ) -> Option<RaycastHit> {
+        self.raycast_query(&RayQuery {
+            footprint: *footprint,
+            ..RayQuery::new(origin, dir)
+        })
+    }
+
+    fn raycast(&self, origin: Point3<f32>, dir: Vector3<f32>) -> Option<RaycastHit> {
+        self.raycast_query(&RayQuery::new(origin, dir))
+    }
+
+    /// Returns true if the ray hits anything in the interval of the query,
+    /// e.g. for shadow rays.
+    fn occluded(&self, query: &RayQuery) -> bool {
+        self.raycast_query(&RayQuery {
+            any_hit: true,
+            ..*query
+        })
+        .is_some()
+    }
+}
+
+/// Several objects in the same space, e.g. the versions of an octree. The
+/// interval of the query shrinks to the closest hit so far, so objects behind
+/// it stop their traversal early.
+impl<T: Raycastable> Raycastable for [T] {
+    fn raycast_query(&self, query: &RayQuery) -> Option<RaycastHit> {
+        let mut query = *query;
+        let mut closest = None;
+        for object in self {
+            if let Some(hit) = object.raycast_query(&query) {
+                if query.any_hit {
+                    return Some(hit);
+                }
+                query.t_max = hit.t;
+                closest = Some(hit);
+            }
+        }
+        closest
+    }
 }
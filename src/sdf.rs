@@ -0,0 +1,266 @@
+use na::{base::Vector3, geometry::Point3};
+use std::collections::{HashMap, HashSet};
+
+use crate::svo::SparseVoxelOctree;
+
+impl SparseVoxelOctree {
+    /// Euclidean distance from `p` to the closest filled voxel, or minus the
+    /// distance to the closest empty voxel if `p` is inside of a filled one.
+    /// Everything outside of the octree counts as empty. The result is clamped
+    /// to `-max_distance..=max_distance`.
+    pub fn signed_distance(&self, p: Point3<f32>, max_distance: f32) -> f32 {
+        let outside = self
+            .nearest_node(p, max_distance, true)
+            .map_or(max_distance, |(_, distance)| distance);
+        if outside > 0.0 {
+            return outside;
+        }
+        // p lies in a filled voxel, so it is inside of the unit cube as well
+        let border = (0..3)
+            .map(|i| p[i].min(1.0 - p[i]))
+            .fold(f32::MAX, f32::min);
+        let inside = self
+            .nearest_node(p, max_distance, false)
+            .map_or(max_distance, |(_, distance)| distance);
+        -inside.min(border).min(max_distance)
+    }
+}
+
+/// Signed distance field of an octree in a narrow band around its surface.
+/// The grid is divided into bricks, and only the bricks within the band store
+/// distances, sampled at the voxel corners. All other bricks are either far
+/// outside or far inside of the filled voxels and are not stored.
+pub struct SignedDistanceField {
+    size: usize,
+    brick_size: usize,
+    band: f32,
+    // corner samples of each brick in the band, with z changing fastest
+    bricks: HashMap<[usize; 3], Vec<f32>>,
+    // bricks further than `band` inside of the filled voxels
+    inside_bricks: HashSet<[usize; 3]>,
+}
+
+impl SignedDistanceField {
+    /// Samples the signed distance of `svo` for bricks of `brick_size` voxels
+    /// along each axis, a power of two up to the grid size. Distances are
+    /// exact up to `band`, beyond that they are clamped to +/- `band`.
+    pub fn new(svo: &SparseVoxelOctree, brick_size: usize, band: f32) -> SignedDistanceField {
+        assert!(brick_size.is_power_of_two() && brick_size <= svo.size);
+        let mut sdf = SignedDistanceField {
+            size: svo.size,
+            brick_size,
+            band,
+            bricks: HashMap::new(),
+            inside_bricks: HashSet::new(),
+        };
+        // voxels around a brick that can be within the band of its samples
+        let margin = (band * svo.size as f32).ceil() as usize;
+        // Only bricks near filled nodes can be in the band or inside, so the
+        // octree is walked down to the brick size to find them.
+        let mut candidates = HashSet::new();
+        svo.visit(|node| {
+            if !node.is_leaf && node.size > brick_size {
+                return true;
+            }
+            let min = node
+                .min
+                .coords
+                .map(|v| v.saturating_sub(margin) / brick_size);
+            let max = node
+                .min
+                .coords
+                .map(|v| ((v + node.size + margin).min(svo.size) - 1) / brick_size);
+            for bx in min.x..=max.x {
+                for by in min.y..=max.y {
+                    for bz in min.z..=max.z {
+                        candidates.insert([bx, by, bz]);
+                    }
+                }
+            }
+            false
+        });
+        for brick in candidates {
+            sdf.add_brick(svo, brick, margin);
+        }
+        sdf
+    }
+
+    // Samples the brick if it is within the band, or records it if it lies
+    // further inside of the filled voxels.
+    fn add_brick(&mut self, svo: &SparseVoxelOctree, brick: [usize; 3], margin: usize) {
+        let brick_size = self.brick_size;
+        let brick_min = Point3::from(brick) * brick_size;
+        let min = Point3::from(brick_min.coords.map(|v| v.saturating_sub(margin)));
+        let max = Point3::from(
+            brick_min
+                .coords
+                .map(|v| (v + brick_size + margin).min(svo.size)),
+        );
+        let count = svo.count_in_box(min, max);
+        if count == 0 {
+            return;
+        }
+        let volume = (0..3).map(|i| max[i] - min[i]).product::<usize>();
+        let clipped = (0..3).any(|i| max[i] - min[i] < brick_size + 2 * margin);
+        if count == volume && !clipped {
+            self.inside_bricks.insert(brick);
+            return;
+        }
+        let samples = self.sample_brick(svo, brick_min);
+        self.bricks.insert(brick, samples);
+    }
+
+    fn sample_brick(&self, svo: &SparseVoxelOctree, brick_min: Point3<usize>) -> Vec<f32> {
+        let corners = self.brick_size + 1;
+        let mut samples = Vec::with_capacity(corners * corners * corners);
+        for x in 0..corners {
+            for y in 0..corners {
+                for z in 0..corners {
+                    let corner = brick_min + Vector3::new(x, y, z);
+                    let p = Point3::from(corner.coords.map(|v| v as f32 / self.size as f32));
+                    samples.push(svo.signed_distance(p, self.band));
+                }
+            }
+        }
+        samples
+    }
+
+    /// Number of bricks that store samples.
+    pub fn brick_count(&self) -> usize {
+        self.bricks.len()
+    }
+
+    pub fn size_bytes(&self) -> usize {
+        let corners = (self.brick_size + 1).pow(3);
+        self.bricks.len() * corners * std::mem::size_of::<f32>()
+    }
+
+    /// Signed distance at `p` in world space, interpolated between the
+    /// samples of the brick that contains it. Outside of the octree, the
+    /// distance to the closest point on its border is added.
+    pub fn distance(&self, p: Point3<f32>) -> f32 {
+        let border = Point3::from(p.coords.map(|v| v.clamp(0.0, 1.0)));
+        if border != p {
+            return ((p - border).norm() + self.distance(border)).min(self.band);
+        }
+        let q = p.coords * self.size as f32;
+        let last_brick = self.size / self.brick_size - 1;
+        let brick = q.map(|v| (v as usize / self.brick_size).min(last_brick));
+        let samples = match self.bricks.get(&[brick.x, brick.y, brick.z]) {
+            Some(samples) => samples,
+            None if self.inside_bricks.contains(&[brick.x, brick.y, brick.z]) => return -self.band,
+            None => return self.band,
+        };
+
+        let local = q - brick.map(|v| (v * self.brick_size) as f32);
+        let cell = local.map(|v| (v as usize).min(self.brick_size - 1));
+        let t = local - cell.map(|v| v as f32);
+        let corners = self.brick_size + 1;
+        let sample = |x: usize, y: usize, z: usize| {
+            samples[((cell.x + x) * corners + cell.y + y) * corners + cell.z + z]
+        };
+        let lerp = |a: f32, b: f32, t: f32| a + (b - a) * t;
+        let x00 = lerp(sample(0, 0, 0), sample(1, 0, 0), t.x);
+        let x10 = lerp(sample(0, 1, 0), sample(1, 1, 0), t.x);
+        let x01 = lerp(sample(0, 0, 1), sample(1, 0, 1), t.x);
+        let x11 = lerp(sample(0, 1, 1), sample(1, 1, 1), t.x);
+        lerp(lerp(x00, x10, t.y), lerp(x01, x11, t.y), t.z)
+    }
+}
+
+#[test]
+fn test_signed_distance() {
+    use crate::voxel_grid::VoxelGrid;
+    use rand::{Rng, SeedableRng};
+    use std::convert::TryFrom;
+
+    let size = 16;
+    let mut rng = rand::rngs::StdRng::seed_from_u64(18);
+    let mut voxel_grid = VoxelGrid::new(size);
+    for x in 0..size {
+        for y in 0..size {
+            for z in 0..size {
+                // a ball, a single voxel next to the border and one on it
+                let d = Vector3::new(x as f32, y as f32, z as f32) - Vector3::repeat(7.5);
+                if d.norm() < 6.0 || (x, y, z) == (1, 14, 1) || (x, y, z) == (0, 8, 8) {
+                    voxel_grid.data[x][y][z] = Some(1);
+                }
+            }
+        }
+    }
+    let svo = SparseVoxelOctree::try_from(&voxel_grid).unwrap();
+
+    let brute_force = |p: Point3<f32>| {
+        // distance to the slab of a voxel along one axis
+        let axis = |p: f32, v: usize| {
+            let (lower, upper) = (v as f32 / size as f32, (v + 1) as f32 / size as f32);
+            (lower - p).max(p - upper).max(0.0)
+        };
+        let mut outside = f32::MAX;
+        let mut inside = (0..3)
+            .map(|i| p[i].min(1.0 - p[i]))
+            .fold(f32::MAX, f32::min);
+        for x in 0..size {
+            let dx = axis(p.x, x);
+            for y in 0..size {
+                let dy = axis(p.y, y);
+                for z in 0..size {
+                    let dz = axis(p.z, z);
+                    let distance = (dx * dx + dy * dy + dz * dz).sqrt();
+                    if voxel_grid.data[x][y][z].is_some() {
+                        outside = outside.min(distance);
+                    } else {
+                        inside = inside.min(distance);
+                    }
+                }
+            }
+        }
+        if outside > 0.0 {
+            outside
+        } else {
+            -inside.max(0.0)
+        }
+    };
+    for _ in 0..100 {
+        let p = Point3::new(
+            rng.gen_range(-0.2..1.2),
+            rng.gen_range(-0.2..1.2),
+            rng.gen_range(-0.2..1.2),
+        );
+        let expected = brute_force(p);
+        assert!((svo.signed_distance(p, 10.0) - expected).abs() < 1e-5);
+        let clamped = expected.clamp(-0.1, 0.1);
+        assert!((svo.signed_distance(p, 0.1) - clamped).abs() < 1e-5);
+    }
+
+    let band = 1.0 / size as f32;
+    let sdf = SignedDistanceField::new(&svo, 2, band);
+    assert!(sdf.brick_count() < (size / 2).pow(3));
+    // the samples at the voxel corners are exact
+    for _ in 0..300 {
+        let corner = Point3::new(
+            rng.gen_range(0..=size),
+            rng.gen_range(0..=size),
+            rng.gen_range(0..=size),
+        );
+        let p = Point3::from(corner.coords.map(|v| v as f32 / size as f32));
+        let expected = brute_force(p).clamp(-band, band);
+        assert!((sdf.distance(p) - expected).abs() < 1e-5, "{}", p);
+    }
+    // between them, the distance is interpolated
+    let p = Point3::new(1.5, 14.5, 0.5) / size as f32;
+    assert!((sdf.distance(p) - 0.5 * band).abs() < 1e-5);
+    // outside of the octree, the distance grows from the border
+    let p = Point3::new(-0.25, 8.5, 8.5) / size as f32;
+    assert!((sdf.distance(p) - brute_force(p)).abs() < 1e-5);
+    let p = Point3::new(-4.0, 8.5, 8.5) / size as f32;
+    assert_eq!(sdf.distance(p), band);
+    // bricks far inside and outside are not stored
+    assert_eq!(sdf.distance(Point3::new(0.5, 0.5, 0.5)), -band);
+    assert_eq!(sdf.distance(Point3::new(0.9, 0.1, 0.9)), band);
+
+    let empty = SparseVoxelOctree::try_from(&VoxelGrid::new(size)).unwrap();
+    let sdf = SignedDistanceField::new(&empty, 4, band);
+    assert_eq!(sdf.brick_count(), 0);
+    assert_eq!(sdf.distance(Point3::new(0.5, 0.5, 0.5)), band);
+}
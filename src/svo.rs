@@ -1,39 +1,440 @@
 use na::{base::Vector3, geometry::Point3};
+use std::collections::{HashMap, VecDeque};
+use std::convert::TryFrom;
+use std::fmt;
 
-use crate::raycast::{RaycastHit, Raycastable};
-use crate::voxel_grid::VoxelGrid;
+use crate::morton;
+use crate::raycast::{RayQuery, RaycastHit, Raycastable};
+use crate::traverse::leaf_voxel;
+use crate::voxel_grid::{self, VoxelGrid};
 
-type SVONode = [u32; 2];
+// node[0] = empty (bit 31) | leaf (bit 30) | far (bit 29) | child tile index (bits 0..28)
+// node[1] = colour
+//
+// If the child tile index does not fit into 29 bits, the far bit is set and the
+// low bits hold the offset from the node forwards to a far slot instead, whose
+// two words hold the 64-bit index of the child tile. Far slots are written right
+// after the tile of the node, except for the root, whose far slot is reserved at
+// index 1 because the root tile is written last.
+pub(crate) type SVONode = [u32; 2];
 
-fn create_node() -> SVONode {
+const FAR_BIT: u32 = 1 << 29;
+const CHILD_MASK: u32 = FAR_BIT - 1;
+const ROOT_FAR_SLOT: usize = 1;
+
+/// Largest supported grid size, as morton codes hold 21 bits per axis.
+pub const MAX_SIZE: usize = 1 << 21;
+
+#[derive(Debug, PartialEq)]
+pub enum BuildError {
+    /// The grid size is not a power of two between 2 and `MAX_SIZE`.
+    InvalidSize(usize),
+    /// A voxel lies outside of the grid.
+    OutOfBounds(Point3<usize>),
+    /// Two octrees that are combined have different grid sizes.
+    SizeMismatch(usize, usize),
+    /// A child tile cannot be referenced from a node, not even through a far
+    /// slot.
+    PointerOutOfRange { node: usize, children: usize },
+}
+
+impl fmt::Display for BuildError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            BuildError::InvalidSize(size) => write!(f, "invalid grid size {}", size),
+            BuildError::OutOfBounds(p) => write!(f, "voxel {} outside of grid", p),
+            BuildError::SizeMismatch(a, b) => write!(f, "grid sizes {} and {} differ", a, b),
+            BuildError::PointerOutOfRange { node, children } => {
+                write!(f, "node {} cannot point to child tile {}", node, children)
+            }
+        }
+    }
+}
+
+impl std::error::Error for BuildError {}
+
+// Returns the depth of the leaves of an octree with the given size.
+fn octree_levels(size: usize) -> Result<usize, BuildError> {
+    if !size.is_power_of_two() || !(2..=MAX_SIZE).contains(&size) {
+        return Err(BuildError::InvalidSize(size));
+    }
+    Ok(size.trailing_zeros() as usize)
+}
+
+pub(crate) fn create_node() -> SVONode {
     [
         1 << 31, // default is an empty node
         0,
     ]
 }
 
-fn set_node(node: &mut SVONode, is_empty: bool, is_leaf: bool, children: usize, color: u32) {
-    node[0] = ((is_empty as u32) << 31) | ((is_leaf as u32) << 30) | (children as u32 & 0x3fffffff);
+// Node pool that only holds the root and its reserved far slot.
+pub(crate) fn root_pool() -> Vec<SVONode> {
+    vec![create_node(); 2]
+}
+
+// Sets a node that points to a tile with a small index. Use `link_child` for
+// arbitrary indices.
+pub(crate) fn set_node(
+    node: &mut SVONode,
+    is_empty: bool,
+    is_leaf: bool,
+    children: usize,
+    color: u32,
+) {
+    assert!(
+        children <= CHILD_MASK as usize,
+        "child tile {} needs a far slot",
+        children
+    );
+    node[0] = ((is_empty as u32) << 31) | ((is_leaf as u32) << 30) | children as u32;
     node[1] = color;
 }
 
-fn is_empty(node: &SVONode) -> bool {
+pub(crate) fn is_empty(node: &SVONode) -> bool {
     (node[0] & 0x80000000) != 0
 }
 
-fn is_leaf(node: &SVONode) -> bool {
+pub(crate) fn is_leaf(node: &SVONode) -> bool {
     (node[0] & 0x40000000) != 0
 }
 
-fn child_idx(node: &SVONode) -> usize {
-    (node[0] & 0x3fffffff) as usize
+pub(crate) fn is_far(node: &SVONode) -> bool {
+    (node[0] & FAR_BIT) != 0
+}
+
+// Index of the child tile of the node at `node_idx`.
+pub(crate) fn child_tile(node_pool: &[SVONode], node_idx: usize) -> usize {
+    let node = node_pool[node_idx];
+    let children = (node[0] & CHILD_MASK) as usize;
+    if is_far(&node) {
+        let far_slot = node_pool[node_idx + children];
+        (far_slot[0] as u64 | (far_slot[1] as u64) << 32) as usize
+    } else {
+        children
+    }
+}
+
+// Points the node at `node_idx` to the child tile at `children`, keeping its
+// flags and colour. Indices that do not fit into the node go to a far slot.
+pub(crate) fn link_child(
+    node_pool: &mut Vec<SVONode>,
+    node_idx: usize,
+    children: usize,
+) -> Result<(), BuildError> {
+    if children <= CHILD_MASK as usize {
+        node_pool[node_idx][0] =
+            (node_pool[node_idx][0] & !(FAR_BIT | CHILD_MASK)) | children as u32;
+        Ok(())
+    } else {
+        link_far(node_pool, node_idx, children)
+    }
+}
+
+// Points the node at `node_idx` to the child tile at `children` through a far
+// slot, which is appended to the pool unless the node is the root.
+fn link_far(
+    node_pool: &mut Vec<SVONode>,
+    node_idx: usize,
+    children: usize,
+) -> Result<(), BuildError> {
+    let far_idx = if node_idx == 0 {
+        ROOT_FAR_SLOT
+    } else {
+        node_pool.push(create_node());
+        node_pool.len() - 1
+    };
+    let offset = far_idx - node_idx;
+    if offset > CHILD_MASK as usize {
+        return Err(BuildError::PointerOutOfRange {
+            node: node_idx,
+            children,
+        });
+    }
+    node_pool[far_idx] = [children as u32, (children as u64 >> 32) as u32];
+    let node = &mut node_pool[node_idx];
+    node[0] = (node[0] & !CHILD_MASK) | FAR_BIT | offset as u32;
+    Ok(())
+}
+
+// Appends a tile and links its interior nodes to the child tiles in
+// `children`, which are ignored for empty nodes and leaves. Returns the index
+// of the tile.
+pub(crate) fn write_tile(
+    node_pool: &mut Vec<SVONode>,
+    tile: &[SVONode; 8],
+    children: &[usize; 8],
+) -> Result<usize, BuildError> {
+    let tile_idx = node_pool.len();
+    node_pool.extend_from_slice(tile);
+    for (slot, node) in tile.iter().enumerate() {
+        if !is_empty(node) && !is_leaf(node) {
+            link_child(node_pool, tile_idx + slot, children[slot])?;
+        }
+    }
+    Ok(tile_idx)
+}
+
+// Appends the subtree below the tile at `tile_idx` of `src` to `dst` in the
+// order the morton builder writes it, and returns the index of the copy.
+// Tiles that were copied before, as recorded in `copied`, are shared instead.
+pub(crate) fn copy_subtree(
+    src: &[SVONode],
+    tile_idx: usize,
+    dst: &mut Vec<SVONode>,
+    copied: &mut HashMap<usize, usize>,
+) -> Result<usize, BuildError> {
+    if let Some(&copy_idx) = copied.get(&tile_idx) {
+        return Ok(copy_idx);
+    }
+    let mut tile = [create_node(); 8];
+    tile.copy_from_slice(&src[tile_idx..tile_idx + 8]);
+    let mut children = [0; 8];
+    for digit in 0..8 {
+        let slot = 7 ^ digit;
+        if !is_empty(&tile[slot]) && !is_leaf(&tile[slot]) {
+            children[slot] = copy_subtree(src, child_tile(src, tile_idx + slot), dst, copied)?;
+        }
+    }
+    let copy_idx = write_tile(dst, &tile, &children)?;
+    copied.insert(tile_idx, copy_idx);
+    Ok(copy_idx)
+}
+
+// Appends the node pool of a subtree that was built on its own and moves its
+// child tile indices along. Indices that no longer fit into a node get a far
+// slot at the end of the pool. Returns the index the subtree starts at.
+fn append_subtree(node_pool: &mut Vec<SVONode>, subtree: &[SVONode]) -> Result<usize, BuildError> {
+    let base = node_pool.len();
+    node_pool.extend_from_slice(subtree);
+    // far slots follow the tile of their node, so they come up in the order
+    // their nodes were seen
+    let mut far_slots = VecDeque::new();
+    for node_idx in base..base + subtree.len() {
+        if far_slots.front() == Some(&node_idx) {
+            far_slots.pop_front();
+            continue;
+        }
+        let node = node_pool[node_idx];
+        if is_empty(&node) || is_leaf(&node) {
+            continue;
+        }
+        let children = child_tile(node_pool, node_idx) + base;
+        if is_far(&node) {
+            let far_idx = node_idx + (node[0] & CHILD_MASK) as usize;
+            node_pool[far_idx] = [children as u32, (children as u64 >> 32) as u32];
+            far_slots.push_back(far_idx);
+        } else {
+            link_child(node_pool, node_idx, children)?;
+        }
+    }
+    Ok(base)
+}
+
+// A node that is not written to a tile yet, and the index of its child tile.
+pub(crate) type NodeRef = (SVONode, usize);
+
+// Children of a node. Empty nodes have empty children and leaves are split into
+// eight leaves of the same colour.
+pub(crate) fn node_children(node_pool: &[SVONode], (node, tile_idx): NodeRef) -> [NodeRef; 8] {
+    if is_empty(&node) {
+        return [(create_node(), 0); 8];
+    }
+    if is_leaf(&node) {
+        return [(node, 0); 8];
+    }
+    let mut children = [(create_node(), 0); 8];
+    for (slot, child) in children.iter_mut().enumerate() {
+        let child_node = node_pool[tile_idx + slot];
+        *child = if is_empty(&child_node) || is_leaf(&child_node) {
+            (child_node, 0)
+        } else {
+            (child_node, child_tile(node_pool, tile_idx + slot))
+        };
+    }
+    children
+}
+
+// Writes the tile of an interior node and returns the node.
+pub(crate) fn write_children(
+    node_pool: &mut Vec<SVONode>,
+    children: &[NodeRef; 8],
+) -> Result<NodeRef, BuildError> {
+    let mut tile = [create_node(); 8];
+    let mut child_tiles = [0; 8];
+    for (slot, &(node, children)) in children.iter().enumerate() {
+        tile[slot] = node;
+        child_tiles[slot] = children;
+    }
+    let tile_idx = write_tile(node_pool, &tile, &child_tiles)?;
+    let mut node = create_node();
+    set_node(&mut node, false, false, 0, filter_colors(&tile));
+    Ok((node, tile_idx))
+}
+
+// Filtered colour of a node from the colours of its child tile, used when a
+// traversal stops above the leaves. RGB is the alpha weighted average of the
+// filled children. Alpha is the average over all eight children, with empty
+// children counting as transparent, so it also encodes the coverage.
+fn filter_colors(tile: &[SVONode]) -> u32 {
+    let mut rgb_sum = [0u32; 3];
+    let mut alpha_sum = 0;
+    for node in tile.iter().filter(|node| !is_empty(node)) {
+        let alpha = node[1] >> 24;
+        for (channel, sum) in rgb_sum.iter_mut().enumerate() {
+            *sum += ((node[1] >> (8 * channel)) & 0xFF) * alpha;
+        }
+        alpha_sum += alpha;
+    }
+    let mut color = ((alpha_sum + 4) / 8) << 24;
+    for (channel, sum) in rgb_sum.iter().enumerate() {
+        let average = (sum + alpha_sum / 2).checked_div(alpha_sum).unwrap_or(0);
+        color |= average << (8 * channel);
+    }
+    color
+}
+
+fn voxel_code(p: Point3<usize>, size: usize) -> Result<u64, BuildError> {
+    if p.x >= size || p.y >= size || p.z >= size {
+        return Err(BuildError::OutOfBounds(p));
+    }
+    Ok(morton::encode_3d(p.x as u64, p.y as u64, p.z as u64))
 }
 
+// Child tile whose slots are still being filled. All nodes of a tile share the
+// parent identified by the morton prefix `parent`.
+struct PendingTile {
+    parent: u64,
+    nodes: [SVONode; 8],
+    children: [usize; 8],
+}
+
+// Node pool of a subtree, and its top node with the index of its child tile.
+type Subtree = (Vec<SVONode>, Option<(SVONode, usize)>);
+
+// Builds the node pool bottom-up from voxels that are pushed in increasing
+// morton order. Once a voxel with a different parent prefix arrives, the
+// subtree of the previous tile is complete and the tile is written to the pool.
+// Tiles therefore end up in the pool in post-order, children before parents.
+struct MortonBuilder {
+    levels: usize,
+    top_depth: usize,
+    node_pool: Vec<SVONode>,
+    pending: Vec<Option<PendingTile>>,
+    // node at `top_depth` and the index of its child tile
+    top: Option<(SVONode, usize)>,
+}
+
+impl MortonBuilder {
+    // `levels` is the depth of the leaves, `top_depth` the depth of the node
+    // that is returned by `finish` instead of being written to a tile.
+    fn new(levels: usize, top_depth: usize, node_pool: Vec<SVONode>) -> MortonBuilder {
+        MortonBuilder {
+            levels,
+            top_depth,
+            node_pool,
+            pending: (0..=levels).map(|_| None).collect(),
+            top: None,
+        }
+    }
+
+    fn push_voxel(&mut self, code: u64, color: u32) -> Result<(), BuildError> {
+        // Write out all tiles of subtrees that cannot contain the new voxel,
+        // so that every subtree is complete before the next one starts.
+        for depth in (self.top_depth + 1..=self.levels).rev() {
+            let parent = code >> (3 * (self.levels - depth + 1));
+            if let Some(tile) = &self.pending[depth] {
+                if tile.parent != parent {
+                    self.flush(depth)?;
+                }
+            }
+        }
+
+        let mut leaf = create_node();
+        set_node(&mut leaf, false, true, 0, color);
+        self.insert(self.levels, code, leaf, 0);
+        Ok(())
+    }
+
+    fn insert(&mut self, depth: usize, prefix: u64, node: SVONode, children: usize) {
+        if depth == self.top_depth {
+            self.top = Some((node, children));
+            return;
+        }
+        let parent = prefix >> 3;
+        let tile = self.pending[depth].get_or_insert_with(|| PendingTile {
+            parent,
+            nodes: [create_node(); 8],
+            children: [0; 8],
+        });
+        let slot = (7 ^ (prefix & 7)) as usize;
+        tile.nodes[slot] = node;
+        tile.children[slot] = children;
+    }
+
+    fn flush(&mut self, depth: usize) -> Result<(), BuildError> {
+        let tile = self.pending[depth].take().unwrap();
+        let tile_idx = write_tile(&mut self.node_pool, &tile.nodes, &tile.children)?;
+
+        let mut node = create_node();
+        set_node(&mut node, false, false, 0, filter_colors(&tile.nodes));
+        self.insert(depth - 1, tile.parent, node, tile_idx);
+        Ok(())
+    }
+
+    // Writes all pending tiles and returns the node pool together with the
+    // node at `top_depth` and its child tile, which is `None` if no voxel was
+    // pushed.
+    fn finish(mut self) -> Result<Subtree, BuildError> {
+        for depth in (self.top_depth + 1..=self.levels).rev() {
+            if self.pending[depth].is_some() {
+                self.flush(depth)?;
+            }
+        }
+        Ok((self.node_pool, self.top))
+    }
+}
+
+/// Sparse voxel octree over a grid of `size` voxels along each axis. Rays and
+/// spatial queries take positions and distances in world space, where the
+/// octree spans [0, 1]^3 and voxel (x, y, z) covers [x, x + 1] / size etc.
+#[derive(Clone)]
 pub struct SparseVoxelOctree {
     pub node_pool: Vec<SVONode>,
+    // edge length of the voxel grid
+    pub size: usize,
 }
 
 impl SparseVoxelOctree {
+    // Builds the octree from the morton codes and colours of all filled voxels
+    // of a grid with the given size, sorted by morton code.
+    fn from_morton_codes(
+        voxels: &[(u64, u32)],
+        size: usize,
+    ) -> Result<SparseVoxelOctree, BuildError> {
+        let levels = octree_levels(size)?;
+        let mut builder = MortonBuilder::new(levels, 0, root_pool());
+        for &(code, color) in voxels {
+            builder.push_voxel(code, color)?;
+        }
+        let (mut node_pool, root) = builder.finish()?;
+        let (root, root_tile_idx) = match root {
+            Some(root) => root,
+            None => {
+                let root_tile_idx = write_tile(&mut node_pool, &[create_node(); 8], &[0; 8])?;
+                let mut root = create_node();
+                set_node(&mut root, false, false, 0, 0);
+                (root, root_tile_idx)
+            }
+        };
+        node_pool[0] = root;
+        link_child(&mut node_pool, 0, root_tile_idx)?;
+        Ok(SparseVoxelOctree { node_pool, size })
+    }
+
+    // Top-down reference builder that samples the voxel grid for every child
+    // cube. It is kept to check the morton builder against.
+    #[cfg(test)]
     fn build_octree(
         &mut self,
         voxel_grid: &VoxelGrid,
@@ -55,10 +456,9 @@ impl SparseVoxelOctree {
 
         for i in 0..8 {
             if voxel_grid.sample(cx[i], cy[i], cz[i], half_size) {
-                let color = (cx[i] | (cy[i] << 8) | (cz[i] << 16)) as u32;
-                //let color = 0xFFFFFF;
                 if half_size != 1 {
                     let child_idx = self.build_octree(voxel_grid, cx[i], cy[i], cz[i], half_size);
+                    let color = filter_colors(&self.node_pool[child_idx..child_idx + 8]);
                     set_node(
                         &mut self.node_pool[node_tile_idx + i],
                         false,
@@ -67,297 +467,515 @@ impl SparseVoxelOctree {
                         color,
                     );
                 } else {
+                    let color = voxel_grid.data[cx[i]][cy[i]][cz[i]].unwrap();
                     set_node(
                         &mut self.node_pool[node_tile_idx + i],
                         false,
                         true,
-                        0x3fffffff,
+                        0,
                         color,
                     );
                 }
             }
         }
-        return node_tile_idx;
-    }
-
-    fn raymarch(
-        &self,
-        mut o: Point3<f32>,
-        mut d: Vector3<f32>,
-        t: &mut f32,
-        color: &mut u32,
-        normal: &mut Vector3<f32>,
-    ) -> bool {
-        // Maximum scale (number of float mantissa bits).
-        const S_MAX: u32 = 23;
-        const EPSILON: f32 = 1e-4; // TODO use exp2f(-S_MAX)
-        let mut stack: [(usize, f32); (S_MAX + 1) as usize] = [(0, 0.0); (S_MAX + 1) as usize];
-
-        o.x += 1.0;
-        o.y += 1.0;
-        o.z += 1.0;
-
-        // Get rid of small ray direction components to avoid division by zero.
-        // TODO copysignf
-        if d.x.abs() < EPSILON {
-            d.x = EPSILON
-        }
-        if d.y.abs() < EPSILON {
-            d.y = EPSILON
-        }
-        if d.z.abs() < EPSILON {
-            d.z = EPSILON
-        }
-
-        // Precompute the coefficients of tx(x), ty(y), and tz(z).
-        let tx_coef: f32 = 1.0 / -d.x.abs();
-        let ty_coef: f32 = 1.0 / -d.y.abs();
-        let tz_coef: f32 = 1.0 / -d.z.abs();
-
-        let mut tx_bias: f32 = tx_coef * o.x;
-        let mut ty_bias: f32 = ty_coef * o.y;
-        let mut tz_bias: f32 = tz_coef * o.z;
-
-        let mut octant_mask: u32 = 7;
-        if d.x > 0.0 {
-            octant_mask ^= 1;
-            tx_bias = 3.0 * tx_coef - tx_bias;
-        }
-        if d.y > 0.0 {
-            octant_mask ^= 2;
-            ty_bias = 3.0 * ty_coef - ty_bias;
-        }
-        if d.z > 0.0 {
-            octant_mask ^= 4;
-            tz_bias = 3.0 * tz_coef - tz_bias;
-        }
-
-        // Initialize the active span of t-values.
-        let mut t_min: f32 = (2.0 * tx_coef - tx_bias)
-            .max(2.0 * ty_coef - ty_bias)
-            .max(2.0 * tz_coef - tz_bias);
-        let mut t_max: f32 = (tx_coef - tx_bias)
-            .min(ty_coef - ty_bias)
-            .min(tz_coef - tz_bias);
-        t_min = t_min.max(0.0);
-
-        let mut parent_idx = 0;
-        let mut cur = 0;
-        let mut cur_node = self.node_pool[0];
-        let mut idx: u32 = 0;
-        let mut pos = Point3::<f32>::new(1.0, 1.0, 1.0);
-        let mut scale: u32 = S_MAX - 1;
-        let mut scale_exp2: f32 = 0.5; // exp2f(scale - s_max)
-        let mut step_mask: u32 = 0;
-
-        if 1.5 * tx_coef - tx_bias > t_min {
-            idx ^= 1;
-            pos.x = 1.5;
-        }
-        if 1.5 * ty_coef - ty_bias > t_min {
-            idx ^= 2;
-            pos.y = 1.5;
-        }
-        if 1.5 * tz_coef - tz_bias > t_min {
-            idx ^= 4;
-            pos.z = 1.5;
-        }
-
-        // Traverse voxels along the ray as long as the current voxel stays within the octree
-        while scale < S_MAX {
-            // Fetch child descriptor unless it is already valid
-            if cur == 0 {
-                cur_node = self.node_pool[parent_idx];
+        node_tile_idx
+    }
+}
+
+/// Read access to the nodes of an octree, which is all `raymarch` needs.
+/// Child slot `i` covers the lower half of its parent along x if bit 0 of `i`
+/// is set, along y for bit 1 and along z for bit 2.
+pub(crate) trait Traversable {
+    /// Reference to a node, e.g. its index in the node pool.
+    type Ref: Copy;
+
+    fn root(&self) -> Self::Ref;
+
+    /// Child of an interior node in the given slot, or `None` if it is empty.
+    fn child(&self, node: Self::Ref, slot: u32) -> Option<Self::Ref>;
+
+    fn is_leaf(&self, node: Self::Ref) -> bool;
+
+    fn color(&self, node: Self::Ref) -> u32;
+
+    /// Index of a node in the node pool.
+    fn index(&self, node: Self::Ref) -> usize;
+
+    /// Number of voxels along each axis of the grid.
+    fn size(&self) -> usize;
+}
+
+/// Node at which `raymarch` stopped.
+pub(crate) struct RaymarchHit<R> {
+    pub node: R,
+    /// Distance in multiples of the ray direction.
+    pub t: f32,
+    pub normal: Vector3<f32>,
+    pub depth: usize,
+    /// World space min corner of the cube of the node.
+    pub cube_min: Point3<f32>,
+}
+
+pub(crate) fn raymarch<T: Traversable>(
+    octree: &T,
+    query: &RayQuery,
+) -> Option<RaymarchHit<T::Ref>> {
+    let (mut o, mut d, footprint) = (query.origin, query.dir, &query.footprint);
+    // Maximum scale (number of float mantissa bits).
+    const S_MAX: u32 = 23;
+    // exp2f(-S_MAX), the size of the smallest cube that can be traversed
+    const EPSILON: f32 = 1.0 / (1 << S_MAX) as f32;
+    let mut stack: [(T::Ref, f32); (S_MAX + 1) as usize] =
+        [(octree.root(), 0.0); (S_MAX + 1) as usize];
+
+    // t is measured in multiples of d
+    let ray_size_coef = footprint.size_coef * d.norm();
+    let ray_size_bias = footprint.size_bias;
+
+    o.x += 1.0;
+    o.y += 1.0;
+    o.z += 1.0;
+
+    // Get rid of small ray direction components to avoid division by zero.
+    // They keep their sign, so that rays along an axis do not drift towards
+    // the wrong side.
+    if d.x.abs() < EPSILON {
+        d.x = EPSILON.copysign(d.x)
+    }
+    if d.y.abs() < EPSILON {
+        d.y = EPSILON.copysign(d.y)
+    }
+    if d.z.abs() < EPSILON {
+        d.z = EPSILON.copysign(d.z)
+    }
+
+    // Precompute the coefficients of tx(x), ty(y), and tz(z). They are
+    // evaluated as (x - tx_origin) * tx_coef rather than with a single
+    // multiply-add, which loses precision for tiny direction components.
+    let tx_coef: f32 = 1.0 / -d.x.abs();
+    let ty_coef: f32 = 1.0 / -d.y.abs();
+    let tz_coef: f32 = 1.0 / -d.z.abs();
+
+    let mut tx_origin: f32 = o.x;
+    let mut ty_origin: f32 = o.y;
+    let mut tz_origin: f32 = o.z;
+
+    let mut octant_mask: u32 = 7;
+    if d.x > 0.0 {
+        octant_mask ^= 1;
+        tx_origin = 3.0 - tx_origin;
+    }
+    if d.y > 0.0 {
+        octant_mask ^= 2;
+        ty_origin = 3.0 - ty_origin;
+    }
+    if d.z > 0.0 {
+        octant_mask ^= 4;
+        tz_origin = 3.0 - tz_origin;
+    }
+
+    // Initialize the active span of t-values.
+    let mut t_min: f32 = ((2.0 - tx_origin) * tx_coef)
+        .max((2.0 - ty_origin) * ty_coef)
+        .max((2.0 - tz_origin) * tz_coef);
+    let mut t_max: f32 = ((1.0 - tx_origin) * tx_coef)
+        .min((1.0 - ty_origin) * ty_coef)
+        .min((1.0 - tz_origin) * tz_coef);
+    // Rays that start inside of the octree begin at the origin, or at the
+    // start of the interval of the query, in the cube that contains it.
+    t_min = t_min.max(query.t_min);
+    if t_min > query.t_max {
+        return None;
+    }
+
+    let mut parent = octree.root();
+    let mut hit = parent;
+    let mut idx: u32 = 0;
+    let mut pos = Point3::<f32>::new(1.0, 1.0, 1.0);
+    let mut scale: u32 = S_MAX - 1;
+    let mut scale_exp2: f32 = 0.5; // exp2f(scale - s_max)
+    let mut step_mask: u32 = 0;
+
+    if (1.5 - tx_origin) * tx_coef > t_min {
+        idx ^= 1;
+        pos.x = 1.5;
+    }
+    if (1.5 - ty_origin) * ty_coef > t_min {
+        idx ^= 2;
+        pos.y = 1.5;
+    }
+    if (1.5 - tz_origin) * tz_coef > t_min {
+        idx ^= 4;
+        pos.z = 1.5;
+    }
+
+    // Traverse voxels along the ray as long as the current voxel stays within the octree
+    while scale < S_MAX {
+        // Determine maximum t-value of the cube by evaluating
+        // tx(), ty(), and tz() at its corner.
+        let tx_corner: f32 = (pos.x - tx_origin) * tx_coef;
+        let ty_corner: f32 = (pos.y - ty_origin) * ty_coef;
+        let tz_corner: f32 = (pos.z - tz_origin) * tz_coef;
+        let tc_max = tx_corner.min(ty_corner).min(tz_corner);
+
+        // Process voxel if it exists and the active t-span is non-empty.
+        let child = if t_min <= t_max {
+            octree.child(parent, idx ^ octant_mask)
+        } else {
+            None
+        };
+
+        if let Some(child) = child {
+            // Terminate if the voxel is small enough.
+            if tc_max * ray_size_coef + ray_size_bias >= scale_exp2 {
+                hit = child;
+                break;
             }
 
-            // Determine maximum t-value of the cube by evaluating
-            // tx(), ty(), and tz() at its corner.
-            let tx_corner: f32 = pos.x * tx_coef - tx_bias;
-            let ty_corner: f32 = pos.y * ty_coef - ty_bias;
-            let tz_corner: f32 = pos.z * tz_coef - tz_bias;
-            let tc_max = tx_corner.min(ty_corner).min(tz_corner);
-
-            // Process voxel if it exists and the active t-span is non-empty.
-            let child_idx = child_idx(&cur_node) + (idx ^ octant_mask) as usize;
-            let child = self.node_pool[child_idx];
-
-            if !is_empty(&child) && t_min <= t_max {
-                //// TODO Terminate if the voxel is small enough.
-                //if tc_max * ray_size_coef + ray_size_bias >= scale_exp2 {
-                //break;
-                //}
-
-                // INTERSECT
-                // Intersect active t-span with the cube and evaluate
-                // tx(), ty(), and tz() at the center of the voxel.
-                let tv_max: f32 = t_max.min(tc_max);
-                let half: f32 = scale_exp2 * 0.5;
-                let tx_center: f32 = half * tx_coef + tx_corner;
-                let ty_center: f32 = half * ty_coef + ty_corner;
-                let tz_center: f32 = half * tz_coef + tz_corner;
-
-                // Descend to the first child if the resulting t-span is non-empty.
-                if t_min <= tv_max {
-                    if is_leaf(&child) {
-                        cur_node = child;
-                        break;
-                    }
+            // INTERSECT
+            // Intersect active t-span with the cube and evaluate
+            // tx(), ty(), and tz() at the center of the voxel.
+            let tv_max: f32 = t_max.min(tc_max);
+            let half: f32 = scale_exp2 * 0.5;
+            let tx_center: f32 = (pos.x + half - tx_origin) * tx_coef;
+            let ty_center: f32 = (pos.y + half - ty_origin) * ty_coef;
+            let tz_center: f32 = (pos.z + half - tz_origin) * tz_coef;
 
-                    // PUSH
-                    // Write current parent to the stack
-                    stack[scale as usize] = (parent_idx, t_max);
-                    parent_idx = child_idx;
-                    idx = 0;
-                    scale -= 1;
-                    scale_exp2 = half;
-
-                    if tx_center > t_min {
-                        idx ^= 1;
-                        pos.x += scale_exp2;
-                    }
-                    if ty_center > t_min {
-                        idx ^= 2;
-                        pos.y += scale_exp2;
-                    }
-                    if tz_center > t_min {
-                        idx ^= 4;
-                        pos.z += scale_exp2;
-                    }
-                    // Update active t-span and invalidate cached child descriptor.
-                    t_max = tv_max;
-                    cur = 0;
-                    continue;
+            // Descend to the first child if the resulting t-span is non-empty.
+            if t_min <= tv_max {
+                if octree.is_leaf(child) {
+                    hit = child;
+                    break;
                 }
-            }
-            // ADVANCE
-            // Step along the ray
-            step_mask = 0;
-            if tx_corner <= tc_max {
-                step_mask ^= 1;
-                pos.x -= scale_exp2;
-            }
-            if ty_corner <= tc_max {
-                step_mask ^= 2;
-                pos.y -= scale_exp2;
-            }
-            if tz_corner <= tc_max {
-                step_mask ^= 4;
-                pos.z -= scale_exp2;
-            }
 
-            // Update active t-span and flip bits of the child slot index.
-            t_min = tc_max;
-            idx ^= step_mask;
-
-            // Proceed with pop if the bit flips disagree with the ray direction.
-            if (idx & step_mask) != 0 {
-                // POP
-                // Find the highest differing bit between the two positions.
-                let mut differing_bits: u32 = 0;
-                if (step_mask & 1) != 0 {
-                    differing_bits |= pos.x.to_bits() ^ (pos.x + scale_exp2).to_bits();
+                // PUSH
+                // Write current parent to the stack
+                stack[scale as usize] = (parent, t_max);
+                parent = child;
+                idx = 0;
+                scale -= 1;
+                scale_exp2 = half;
+
+                if tx_center > t_min {
+                    idx ^= 1;
+                    pos.x += scale_exp2;
                 }
-                if (step_mask & 2) != 0 {
-                    differing_bits |= pos.y.to_bits() ^ (pos.y + scale_exp2).to_bits();
+                if ty_center > t_min {
+                    idx ^= 2;
+                    pos.y += scale_exp2;
                 }
-                if (step_mask & 4) != 0 {
-                    differing_bits |= pos.z.to_bits() ^ (pos.z + scale_exp2).to_bits();
+                if tz_center > t_min {
+                    idx ^= 4;
+                    pos.z += scale_exp2;
                 }
-                scale = 31 - (differing_bits).leading_zeros(); // position of the highest bit
-                scale_exp2 = f32::from_bits(((scale as i32 - S_MAX as i32 + 127) << 23) as u32); // exp2f(scale - s_max)
-
-                // Restore parent voxel from the stack.
-                parent_idx = stack[scale as usize].0;
-                t_max = stack[scale as usize].1;
-
-                // Round cube position and extract child slot index.
-                let shx: u32 = pos.x.to_bits() >> scale;
-                let shy: u32 = pos.y.to_bits() >> scale;
-                let shz: u32 = pos.z.to_bits() >> scale;
-                pos.x = f32::from_bits(shx << scale);
-                pos.y = f32::from_bits(shy << scale);
-                pos.z = f32::from_bits(shz << scale);
-                idx = (shx & 1) | ((shy & 1) << 1) | ((shz & 1) << 2);
-                cur = 0;
+                // Update active t-span.
+                t_max = tv_max;
+                continue;
             }
         }
+        // ADVANCE
+        // Step along the ray
+        step_mask = 0;
+        if tx_corner <= tc_max {
+            step_mask ^= 1;
+            pos.x -= scale_exp2;
+        }
+        if ty_corner <= tc_max {
+            step_mask ^= 2;
+            pos.y -= scale_exp2;
+        }
+        if tz_corner <= tc_max {
+            step_mask ^= 4;
+            pos.z -= scale_exp2;
+        }
 
-        if scale >= S_MAX {
-            return false;
+        // Update active t-span and flip bits of the child slot index.
+        t_min = tc_max;
+        idx ^= step_mask;
+
+        // Give up beyond the interval of the query.
+        if t_min > query.t_max {
+            return None;
         }
 
-        // this happens for boundary voxels
-        if step_mask == 0 {
-            if 2.0 * tx_coef - tx_bias >= t_min {
-                step_mask ^= 1;
+        // Proceed with pop if the bit flips disagree with the ray direction.
+        if (idx & step_mask) != 0 {
+            // POP
+            // Find the highest differing bit between the two positions.
+            let mut differing_bits: u32 = 0;
+            if (step_mask & 1) != 0 {
+                differing_bits |= pos.x.to_bits() ^ (pos.x + scale_exp2).to_bits();
             }
-            if 2.0 * ty_coef - ty_bias >= t_min {
-                step_mask ^= 2;
+            if (step_mask & 2) != 0 {
+                differing_bits |= pos.y.to_bits() ^ (pos.y + scale_exp2).to_bits();
             }
-            if 2.0 * tz_coef - tz_bias >= t_min {
-                step_mask ^= 4;
+            if (step_mask & 4) != 0 {
+                differing_bits |= pos.z.to_bits() ^ (pos.z + scale_exp2).to_bits();
             }
+            scale = 31 - (differing_bits).leading_zeros(); // position of the highest bit
+            scale_exp2 = f32::from_bits(((scale as i32 - S_MAX as i32 + 127) << 23) as u32); // exp2f(scale - s_max)
+
+            // Restore parent voxel from the stack.
+            parent = stack[scale as usize].0;
+            t_max = stack[scale as usize].1;
+
+            // Round cube position and extract child slot index.
+            let shx: u32 = pos.x.to_bits() >> scale;
+            let shy: u32 = pos.y.to_bits() >> scale;
+            let shz: u32 = pos.z.to_bits() >> scale;
+            pos.x = f32::from_bits(shx << scale);
+            pos.y = f32::from_bits(shy << scale);
+            pos.z = f32::from_bits(shz << scale);
+            idx = (shx & 1) | ((shy & 1) << 1) | ((shz & 1) << 2);
         }
+    }
 
-        let face;
-        if (octant_mask & 1) == 0 && (step_mask & 1) != 0 {
-            face = 3;
-        } else if (octant_mask & 2) == 0 && (step_mask & 2) != 0 {
-            face = 5;
-        } else if (octant_mask & 4) == 0 && (step_mask & 4) != 0 {
-            face = 6;
+    if scale >= S_MAX {
+        return None;
+    }
+
+    // this happens for boundary voxels
+    if step_mask == 0 {
+        if (2.0 - tx_origin) * tx_coef >= t_min {
+            step_mask ^= 1;
+        }
+        if (2.0 - ty_origin) * ty_coef >= t_min {
+            step_mask ^= 2;
+        }
+        if (2.0 - tz_origin) * tz_coef >= t_min {
+            step_mask ^= 4;
+        }
+    }
+
+    // The normal of the face through which the cube was entered. Rays that
+    // start inside of it have none.
+    let mut normal = Vector3::zeros();
+    if step_mask != 0 {
+        let axis = step_mask.trailing_zeros() as usize;
+        normal[axis] = if octant_mask & (1 << axis) == 0 {
+            -1.0
         } else {
-            face = step_mask;
-        }
-
-        match face {
-            // right
-            1 => normal.x = 1.0,
-            // top
-            2 => normal.y = 1.0,
-            // left
-            3 => normal.x = -1.0,
-            // back
-            4 => normal.z = 1.0,
-            // bottom
-            5 => normal.y = -1.0,
-            // front
-            6 => normal.z = -1.0,
-            _ => (),
-        }
-
-        *t = t_min;
-        *color = cur_node[1];
-        return true;
+            1.0
+        };
+    }
+
+    // Undo the mirroring of the cube and move it back to [0, 1]^3.
+    let mut cube_min = pos;
+    for i in 0..3 {
+        if octant_mask & (1 << i) == 0 {
+            cube_min[i] = 3.0 - (pos[i] + scale_exp2);
+        }
+        cube_min[i] -= 1.0;
     }
+    Some(RaymarchHit {
+        node: hit,
+        t: t_min,
+        normal,
+        depth: (S_MAX - scale) as usize,
+        cube_min,
+    })
 }
 
-impl Raycastable for SparseVoxelOctree {
-    fn raycast(&self, origin: Point3<f32>, dir: Vector3<f32>) -> Option<RaycastHit> {
-        let mut color = 0;
-        let mut normal = Vector3::zeros();
-        let mut t = 0.0;
-        if self.raymarch(origin, dir, &mut t, &mut color, &mut normal) {
-            return Some(RaycastHit {
-                color,
-                normal,
-                pos: origin + t * dir,
-            });
+// Raycasts any octree with `raymarch`.
+pub(crate) fn raycast_octree<T: Traversable>(octree: &T, query: &RayQuery) -> Option<RaycastHit> {
+    let hit = raymarch(octree, query)?;
+    let pos = query.origin + hit.t * query.dir;
+    // the voxel of the cube that contains the hit position
+    let size = octree.size();
+    let min = Point3::from(
+        hit.cube_min
+            .coords
+            .map(|v| (v * size as f32).round() as usize),
+    );
+    let voxel = leaf_voxel(pos, min, size >> hit.depth, size);
+    Some(RaycastHit {
+        color: octree.color(hit.node),
+        normal: hit.normal,
+        pos,
+        t: hit.t,
+        voxel,
+        depth: hit.depth,
+        node: octree.index(hit.node),
+    })
+}
+
+impl Traversable for SparseVoxelOctree {
+    type Ref = usize;
+
+    fn root(&self) -> usize {
+        0
+    }
+
+    fn child(&self, node: usize, slot: u32) -> Option<usize> {
+        let child = child_tile(&self.node_pool, node) + slot as usize;
+        if is_empty(&self.node_pool[child]) {
+            None
         } else {
-            return None;
+            Some(child)
+        }
+    }
+
+    fn is_leaf(&self, node: usize) -> bool {
+        is_leaf(&self.node_pool[node])
+    }
+
+    fn color(&self, node: usize) -> u32 {
+        self.node_pool[node][1]
+    }
+
+    fn index(&self, node: usize) -> usize {
+        node
+    }
+
+    fn size(&self) -> usize {
+        self.size
+    }
+}
+
+impl Raycastable for SparseVoxelOctree {
+    fn raycast_query(&self, query: &RayQuery) -> Option<RaycastHit> {
+        raycast_octree(self, query)
+    }
+}
+
+impl SparseVoxelOctree {
+    /// Builds the octree from the coordinates and RGBA colours of all filled
+    /// voxels of a grid with the given size. Only the voxel list is held in
+    /// memory, never a dense grid. Voxels may be given in any order. If a
+    /// voxel is given more than once, its first colour is used.
+    pub fn from_voxels<I>(size: usize, voxels: I) -> Result<SparseVoxelOctree, BuildError>
+    where
+        I: IntoIterator<Item = (Point3<usize>, u32)>,
+    {
+        let mut voxels: Vec<(u64, u32)> = voxels
+            .into_iter()
+            .map(|(p, color)| Ok((voxel_code(p, size)?, color)))
+            .collect::<Result<_, _>>()?;
+        voxels.sort_by_key(|v| v.0);
+        voxels.dedup_by_key(|v| v.0);
+        SparseVoxelOctree::from_morton_codes(&voxels, size)
+    }
+
+    /// Same as `from_voxels`, but the subtrees of the eight top-level octants
+    /// are sorted and built on separate threads. The resulting node pool is
+    /// identical to the one of the serial build, unless it needs far slots,
+    /// which are then appended after the subtrees instead of after their tiles.
+    pub fn from_voxels_parallel<I>(size: usize, voxels: I) -> Result<SparseVoxelOctree, BuildError>
+    where
+        I: IntoIterator<Item = (Point3<usize>, u32)>,
+    {
+        let levels = octree_levels(size)?;
+        let octant_shift = 3 * (levels - 1);
+        let mut octants = vec![Vec::<(u64, u32)>::new(); 8];
+        for (p, color) in voxels {
+            let code = voxel_code(p, size)?;
+            octants[(code >> octant_shift) as usize].push((code, color));
+        }
+
+        let subtrees: Vec<Result<Subtree, BuildError>> = std::thread::scope(|scope| {
+            let workers: Vec<_> = octants
+                .into_iter()
+                .map(|mut voxels| {
+                    scope.spawn(move || {
+                        voxels.sort_by_key(|v| v.0);
+                        voxels.dedup_by_key(|v| v.0);
+                        let mut builder = MortonBuilder::new(levels, 1, Vec::new());
+                        for (code, color) in voxels {
+                            builder.push_voxel(code, color)?;
+                        }
+                        builder.finish()
+                    })
+                })
+                .collect();
+            workers.into_iter().map(|w| w.join().unwrap()).collect()
+        });
+
+        // Stitch the subtrees together in morton order, like the serial
+        // builder would have written them, followed by the root tile.
+        let mut node_pool = root_pool();
+        let mut root_tile = [create_node(); 8];
+        let mut root_children = [0; 8];
+        for (octant, subtree) in subtrees.into_iter().enumerate() {
+            let (subtree_pool, top) = subtree?;
+            let base = append_subtree(&mut node_pool, &subtree_pool)?;
+            if let Some((top, children)) = top {
+                root_tile[7 ^ octant] = top;
+                root_children[7 ^ octant] = children + base;
+            }
+        }
+        let root_tile_idx = write_tile(&mut node_pool, &root_tile, &root_children)?;
+        set_node(
+            &mut node_pool[0],
+            false,
+            false,
+            0,
+            filter_colors(&root_tile),
+        );
+        link_child(&mut node_pool, 0, root_tile_idx)?;
+        Ok(SparseVoxelOctree { node_pool, size })
+    }
+
+    pub fn from_csv(path: String) -> Result<SparseVoxelOctree, Box<dyn std::error::Error>> {
+        let (size, voxels) = voxel_grid::read_csv(&path)?;
+        // Rows go straight into the octants. Reading stops at the first bad
+        // row, whose error is returned once the octree is built.
+        let mut error = None;
+        let voxels = voxels.scan((), |_, voxel| voxel.map_err(|e| error = Some(e)).ok());
+        let svo = SparseVoxelOctree::from_voxels_parallel(size, voxels)?;
+        match error {
+            Some(error) => Err(error),
+            None => Ok(svo),
         }
     }
 }
 
-impl From<&VoxelGrid> for SparseVoxelOctree {
-    fn from(voxel_grid: &VoxelGrid) -> SparseVoxelOctree {
-        let mut svo = SparseVoxelOctree {
-            node_pool: Vec::<SVONode>::new(),
-        };
-        svo.node_pool.push(create_node());
-        set_node(&mut svo.node_pool[0], false, false, 1, 0xFF00FF);
-        svo.build_octree(&voxel_grid, 0, 0, 0, voxel_grid.size);
-        return svo;
+impl TryFrom<&VoxelGrid> for SparseVoxelOctree {
+    type Error = BuildError;
+
+    fn try_from(voxel_grid: &VoxelGrid) -> Result<SparseVoxelOctree, BuildError> {
+        let mut voxels = Vec::new();
+        for x in 0..voxel_grid.size {
+            for y in 0..voxel_grid.size {
+                for z in 0..voxel_grid.size {
+                    if let Some(color) = voxel_grid.data[x][y][z] {
+                        let code = morton::encode_3d(x as u64, y as u64, z as u64);
+                        voxels.push((code, color));
+                    }
+                }
+            }
+        }
+        voxels.sort_unstable_by_key(|v| v.0);
+        SparseVoxelOctree::from_morton_codes(&voxels, voxel_grid.size)
+    }
+}
+
+impl SparseVoxelOctree {
+    /// Returns all filled voxels with their colours, leaf by leaf in morton
+    /// order. Leaves above the lowest level yield every voxel they cover.
+    pub fn voxels(&self) -> impl Iterator<Item = (Point3<usize>, u32)> + '_ {
+        self.leaves().flat_map(|leaf| {
+            let (min, size) = (leaf.min, leaf.size);
+            (0..size * size * size).map(move |i| {
+                let offset = Vector3::new(i / (size * size), (i / size) % size, i % size);
+                (min + offset, leaf.color)
+            })
+        })
+    }
+
+    /// Writes the filled voxels in the csv format of `from_csv`. The file name
+    /// has to end in the grid size, e.g. `dragon_512.csv`.
+    pub fn write_csv(&self, path: &str) -> Result<(), Box<dyn std::error::Error>> {
+        voxel_grid::write_csv(path, self.size, self.voxels())
+    }
+}
+
+impl From<&SparseVoxelOctree> for VoxelGrid {
+    fn from(svo: &SparseVoxelOctree) -> VoxelGrid {
+        let mut voxel_grid = VoxelGrid::new(svo.size);
+        for (p, color) in svo.voxels() {
+            voxel_grid.data[p.x][p.y][p.z] = Some(color);
+        }
+        voxel_grid
     }
 }
 
@@ -366,3 +984,788 @@ impl SparseVoxelOctree {
         std::mem::size_of::<SVONode>() * self.node_pool.len()
     }
 }
+
+#[cfg(test)]
+fn build_recursive(voxel_grid: &VoxelGrid) -> SparseVoxelOctree {
+    let mut svo = SparseVoxelOctree {
+        node_pool: Vec::<SVONode>::new(),
+        size: voxel_grid.size,
+    };
+    svo.node_pool = root_pool();
+    let root_tile_idx = svo.build_octree(voxel_grid, 0, 0, 0, voxel_grid.size);
+    let color = filter_colors(&svo.node_pool[root_tile_idx..root_tile_idx + 8]);
+    set_node(&mut svo.node_pool[0], false, false, root_tile_idx, color);
+    svo
+}
+
+// Compares the subtrees below node `a` of `svo_a` and node `b` of `svo_b`,
+// ignoring where their tiles are located in the node pools.
+#[cfg(test)]
+pub(crate) fn same_subtree(
+    svo_a: &SparseVoxelOctree,
+    a: usize,
+    svo_b: &SparseVoxelOctree,
+    b: usize,
+) -> bool {
+    let (node_a, node_b) = (svo_a.node_pool[a], svo_b.node_pool[b]);
+    if is_empty(&node_a) != is_empty(&node_b) || is_leaf(&node_a) != is_leaf(&node_b) {
+        return false;
+    }
+    if is_empty(&node_a) {
+        return true;
+    }
+    if node_a[1] != node_b[1] {
+        return false;
+    }
+    if is_leaf(&node_a) {
+        return true;
+    }
+    let (tile_a, tile_b) = (
+        child_tile(&svo_a.node_pool, a),
+        child_tile(&svo_b.node_pool, b),
+    );
+    (0..8).all(|i| same_subtree(svo_a, tile_a + i, svo_b, tile_b + i))
+}
+
+#[test]
+fn test_morton_builder() {
+    use rand::SeedableRng;
+    let mut rng = rand::rngs::StdRng::seed_from_u64(1);
+    for &size in &[2, 4, 8, 16, 32] {
+        for &density in &[0.0, 0.05, 0.5, 1.0] {
+            let voxel_grid = random_grid(&mut rng, size, density);
+            let expected = build_recursive(&voxel_grid);
+            let svo = SparseVoxelOctree::try_from(&voxel_grid).unwrap();
+            assert!(same_subtree(&expected, 0, &svo, 0));
+            assert_eq!(expected.size_bytes(), svo.size_bytes());
+        }
+    }
+}
+
+#[test]
+fn test_from_voxels() {
+    use rand::{seq::SliceRandom, SeedableRng};
+    let mut rng = rand::rngs::StdRng::seed_from_u64(2);
+    let size = 16;
+    let mut voxel_grid = VoxelGrid::new(size);
+    let mut voxels = random_voxels(&mut rng, size, 300);
+    voxels.retain(|&(p, color)| {
+        let first = voxel_grid.data[p.x][p.y][p.z].is_none();
+        voxel_grid.data[p.x][p.y][p.z].get_or_insert(color);
+        first
+    });
+    // arbitrary order must not matter, repeated voxels keep their first colour
+    voxels.shuffle(&mut rng);
+    let duplicates: Vec<_> = voxels[..50].iter().map(|v| (v.0, 0)).collect();
+    voxels.extend(duplicates);
+
+    let expected = SparseVoxelOctree::try_from(&voxel_grid).unwrap();
+    let svo = SparseVoxelOctree::from_voxels(size, voxels).unwrap();
+    assert_eq!(expected.node_pool, svo.node_pool);
+}
+
+// Grid whose voxels are filled with the given probability, in random colours
+// other than zero.
+#[cfg(test)]
+pub(crate) fn random_grid(rng: &mut impl rand::Rng, size: usize, density: f64) -> VoxelGrid {
+    let mut voxel_grid = VoxelGrid::new(size);
+    for x in 0..size {
+        for y in 0..size {
+            for z in 0..size {
+                if rng.gen_bool(density) {
+                    voxel_grid.data[x][y][z] = Some(rng.gen_range(1..u32::MAX));
+                }
+            }
+        }
+    }
+    voxel_grid
+}
+
+// Random voxels of a grid with the given size, which may repeat, in random
+// colours other than zero.
+#[cfg(test)]
+pub(crate) fn random_voxels(
+    rng: &mut impl rand::Rng,
+    size: usize,
+    count: usize,
+) -> Vec<(Point3<usize>, u32)> {
+    (0..count)
+        .map(|_| {
+            let p = Point3::new(
+                rng.gen_range(0..size),
+                rng.gen_range(0..size),
+                rng.gen_range(0..size),
+            );
+            (p, rng.gen_range(1..u32::MAX))
+        })
+        .collect()
+}
+
+#[cfg(test)]
+pub(crate) fn assert_same_raycasts<A: Raycastable, B: Raycastable>(a: &A, b: &B, seed: u64) {
+    use rand::{Rng, SeedableRng};
+    let mut rng = rand::rngs::StdRng::seed_from_u64(seed);
+    for _ in 0..2000 {
+        let origin = Point3::new(
+            rng.gen_range(-1.0..2.0),
+            rng.gen_range(-1.0..2.0),
+            rng.gen_range(-1.0..2.0),
+        );
+        let target = Point3::new(rng.gen::<f32>(), rng.gen::<f32>(), rng.gen::<f32>());
+        let dir = (target - origin).normalize();
+        match (a.raycast(origin, dir), b.raycast(origin, dir)) {
+            (None, None) => (),
+            (Some(hit_a), Some(hit_b)) => {
+                assert_eq!(hit_a.color, hit_b.color);
+                assert_eq!(hit_a.normal, hit_b.normal);
+                assert!((hit_a.pos - hit_b.pos).norm() < 1e-4);
+                assert!((hit_a.t - hit_b.t).abs() < 1e-4);
+                assert_eq!(hit_a.voxel, hit_b.voxel);
+            }
+            _ => panic!("hit mismatch for ray {} {}", origin, dir),
+        }
+    }
+}
+
+#[test]
+fn test_parallel_build() {
+    use rand::SeedableRng;
+    let mut rng = rand::rngs::StdRng::seed_from_u64(3);
+    for &size in &[2, 8, 32] {
+        for &count in &[0, 1, 20, 2000] {
+            let voxels: Vec<(Point3<usize>, u32)> = random_voxels(&mut rng, size, count);
+            let serial = SparseVoxelOctree::from_voxels(size, voxels.iter().cloned()).unwrap();
+            let parallel = SparseVoxelOctree::from_voxels_parallel(size, voxels).unwrap();
+            // without far slots, both builds write the same node pool
+            assert!(serial.node_pool.iter().all(|node| !is_far(node)));
+            assert_eq!(serial.node_pool, parallel.node_pool);
+            assert_same_raycasts(&serial, &parallel, (size + count) as u64);
+        }
+    }
+}
+
+#[test]
+fn test_voxel_colors() {
+    use crate::voxel_grid::rgba;
+    let red = rgba(255, 0, 0, 255);
+    let blue = rgba(0, 0, 255, 128);
+    let svo = SparseVoxelOctree::from_voxels(
+        256,
+        vec![(Point3::new(0, 0, 0), red), (Point3::new(255, 0, 0), blue)],
+    )
+    .unwrap();
+    let hit = svo
+        .raycast(Point3::new(-1.0, 0.001, 0.001), Vector3::new(1.0, 0.0, 0.0))
+        .unwrap();
+    assert_eq!(hit.color, red);
+    let hit = svo
+        .raycast(Point3::new(2.0, 0.001, 0.001), Vector3::new(-1.0, 0.0, 0.0))
+        .unwrap();
+    assert_eq!(hit.color, blue);
+}
+
+#[test]
+fn test_filtered_colors() {
+    use crate::voxel_grid::rgba;
+    let svo = SparseVoxelOctree::from_voxels(
+        4,
+        vec![
+            (Point3::new(0, 0, 0), rgba(255, 0, 0, 255)),
+            (Point3::new(1, 0, 0), rgba(0, 0, 255, 255)),
+            (Point3::new(3, 3, 3), rgba(0, 255, 0, 85)),
+        ],
+    )
+    .unwrap();
+    let root = svo.node_pool[0];
+    let root_tile_idx = child_tile(&svo.node_pool, 0);
+    let octants = &svo.node_pool[root_tile_idx..root_tile_idx + 8];
+    // the octant at the origin is a quarter covered by red and blue
+    assert_eq!(octants[7][1], rgba(128, 0, 128, 64));
+    // the opposite octant has a single translucent green voxel
+    assert_eq!(octants[0][1], rgba(0, 255, 0, 11));
+    // red and blue dominate the root because of their higher alpha
+    assert_eq!(root[1], rgba(109, 37, 109, 9));
+}
+
+#[test]
+fn test_footprint_termination() {
+    use crate::raycast::RayFootprint;
+    use crate::voxel_grid::rgba;
+    let red = rgba(255, 0, 0, 255);
+    let svo = SparseVoxelOctree::from_voxels(16, vec![(Point3::new(0, 0, 0), red)]).unwrap();
+    let dir = Vector3::new(1.0, 0.0, 0.0);
+
+    // a thin ray only hits the voxel itself
+    let hit = svo.raycast(Point3::new(-1.0, 0.01, 0.01), dir).unwrap();
+    assert_eq!(hit.color, red);
+    assert!(svo.raycast(Point3::new(-1.0, 0.3, 0.3), dir).is_none());
+
+    // a wide ray stops at the octant and returns its filtered colour
+    let footprint = RayFootprint {
+        size_coef: 0.0,
+        size_bias: 0.5,
+    };
+    for &origin in &[Point3::new(-1.0, 0.01, 0.01), Point3::new(-1.0, 0.3, 0.3)] {
+        let hit = svo.raycast_footprint(origin, dir, &footprint).unwrap();
+        assert_eq!(hit.color, rgba(255, 0, 0, 1));
+        assert_eq!(hit.normal, Vector3::new(-1.0, 0.0, 0.0));
+        assert!(hit.pos.x.abs() < 1e-4);
+    }
+
+    // a cone stops at coarser nodes the further away they are
+    let footprint = RayFootprint::from_cone_angle(0.05);
+    let near = svo.raycast_footprint(Point3::new(-0.1, 0.01, 0.01), dir, &footprint);
+    let far = svo.raycast_footprint(Point3::new(-20.0, 0.01, 0.01), dir, &footprint);
+    assert_eq!(near.unwrap().color, red);
+    assert_eq!(far.unwrap().color, rgba(255, 0, 0, 1));
+}
+
+#[test]
+fn test_raycast_hit() {
+    use crate::raycast::RayFootprint;
+    let mut svo = SparseVoxelOctree::from_voxels(16, vec![(Point3::new(3, 5, 7), 9)]).unwrap();
+    let origin = Point3::new(1.0, 5.5 / 16.0, 7.5 / 16.0);
+    let dir = Vector3::new(-2.0, 0.0, 0.0);
+    let hit = svo.raycast(origin, dir).unwrap();
+    assert!((hit.t - (1.0 - 4.0 / 16.0) / 2.0).abs() < 1e-6);
+    assert!((hit.pos - (origin + hit.t * dir)).norm() < 1e-6);
+    assert_eq!((hit.voxel, hit.depth), (Point3::new(3, 5, 7), 4));
+    let node = svo.node_pool[hit.node];
+    assert!(is_leaf(&node) && node[1] == 9);
+
+    // a wide ray stops at an interior node
+    let footprint = RayFootprint {
+        size_coef: 0.0,
+        size_bias: 0.2,
+    };
+    let hit = svo.raycast_footprint(origin, dir, &footprint).unwrap();
+    assert_eq!((hit.voxel, hit.depth), (Point3::new(3, 5, 7), 3));
+    assert!(!is_leaf(&svo.node_pool[hit.node]));
+
+    // the voxel of a larger leaf at the hit position
+    svo.fill_box(Point3::new(8, 0, 0), Point3::new(16, 8, 8), 2)
+        .unwrap();
+    let hit = svo.raycast(origin, dir).unwrap();
+    assert_eq!(
+        (hit.voxel, hit.depth, hit.color),
+        (Point3::new(15, 5, 7), 1, 2)
+    );
+
+    // the hit voxel can be edited directly
+    svo.clear_box(Point3::new(8, 0, 0), Point3::new(16, 8, 8))
+        .unwrap();
+    let hit = svo.raycast(origin, dir).unwrap();
+    svo.clear_voxel(hit.voxel).unwrap();
+    assert!(svo.raycast(origin, dir).is_none());
+}
+
+#[test]
+fn test_ray_query() {
+    use rand::{Rng, SeedableRng};
+    let size = 16;
+    let mut rng = rand::rngs::StdRng::seed_from_u64(25);
+    let voxel_grid = random_grid(&mut rng, size, 0.05);
+    let svo = SparseVoxelOctree::try_from(&voxel_grid).unwrap();
+    for _ in 0..1000 {
+        let origin = Point3::new(
+            rng.gen_range(-0.5..1.5),
+            rng.gen_range(-0.5..1.5),
+            rng.gen_range(-0.5..1.5),
+        );
+        let target = Point3::new(rng.gen(), rng.gen(), rng.gen());
+        let t_min = rng.gen_range(0.0..0.5);
+        let query = RayQuery {
+            t_min,
+            t_max: rng.gen_range(t_min..2.0),
+            ..RayQuery::new(origin, target - origin)
+        };
+        // the ray from the start of the interval
+        let start = origin + t_min * query.dir;
+        let expected = brute_force_raycast(&voxel_grid, start, query.dir, false)
+            .map(|(t, voxel)| (t_min + t, voxel))
+            .filter(|&(t, _)| t <= query.t_max);
+        assert_eq!(svo.occluded(&query), expected.is_some());
+        let hits = [svo.raycast_query(&query), voxel_grid.raycast_query(&query)];
+        for hit in &hits {
+            match (hit, expected) {
+                (None, None) => (),
+                (Some(hit), Some((t, voxel))) => {
+                    assert!((hit.t - t).abs() < 1e-4);
+                    assert_eq!(hit.voxel, voxel);
+                }
+                _ => panic!("hit mismatch for {:?}", query),
+            }
+        }
+    }
+
+    // the closest hit of several objects
+    let mut front = VoxelGrid::new(4);
+    front.data[1][1][1] = Some(1);
+    let mut back = VoxelGrid::new(4);
+    back.data[3][1][1] = Some(2);
+    let objects = [back, front];
+    let query = RayQuery::new(Point3::new(-0.5, 0.375, 0.375), Vector3::x());
+    let hit = objects.raycast_query(&query).unwrap();
+    assert_eq!((hit.color, hit.t), (1, 0.75));
+    // any hit is enough to occlude
+    let hit = objects
+        .raycast_query(&RayQuery {
+            any_hit: true,
+            ..query
+        })
+        .unwrap();
+    assert_eq!(hit.color, 2);
+    assert!(objects.occluded(&query));
+    let short = RayQuery {
+        t_max: 0.5,
+        ..query
+    };
+    assert!(!objects.occluded(&short));
+}
+
+#[test]
+fn test_far_pointers() {
+    use rand::SeedableRng;
+    let mut rng = rand::rngs::StdRng::seed_from_u64(10);
+    let voxels: Vec<_> = random_voxels(&mut rng, 32, 1000);
+    let svo = SparseVoxelOctree::from_voxels(32, voxels).unwrap();
+    assert!(svo.node_pool.iter().all(|node| !is_far(node)));
+
+    // route every child pointer through a far slot
+    let mut far = svo.clone();
+    for node_idx in 0..svo.node_pool.len() {
+        let node = svo.node_pool[node_idx];
+        if node_idx != ROOT_FAR_SLOT && !is_empty(&node) && !is_leaf(&node) {
+            let children = child_tile(&svo.node_pool, node_idx);
+            link_far(&mut far.node_pool, node_idx, children).unwrap();
+        }
+    }
+    assert!(is_far(&far.node_pool[0]));
+    assert!(same_subtree(&svo, 0, &far, 0));
+    assert_same_raycasts(&svo, &far, 10);
+
+    // indices beyond 29 bits are stored in far slots of 64 bits
+    let mut node_pool = root_pool();
+    set_node(&mut node_pool[0], false, false, 0, 0);
+    link_child(&mut node_pool, 0, 1 << 40).unwrap();
+    assert_eq!(node_pool.len(), 2);
+    assert_eq!(child_tile(&node_pool, 0), 1 << 40);
+    let tile = [node_pool[0]; 8];
+    let tile_idx = write_tile(&mut node_pool, &tile, &[3 << 30; 8]).unwrap();
+    assert_eq!(node_pool.len(), tile_idx + 16);
+    assert!((0..8).all(|slot| child_tile(&node_pool, tile_idx + slot) == 3 << 30));
+
+    assert_eq!(
+        SparseVoxelOctree::from_voxels(12, vec![]).err(),
+        Some(BuildError::InvalidSize(12))
+    );
+    let p = Point3::new(0, 4, 0);
+    assert_eq!(
+        SparseVoxelOctree::from_voxels_parallel(4, vec![(p, 0)]).err(),
+        Some(BuildError::OutOfBounds(p))
+    );
+}
+
+#[test]
+fn test_voxel_roundtrip() {
+    use rand::SeedableRng;
+    let mut rng = rand::rngs::StdRng::seed_from_u64(16);
+    for &(size, density) in &[(2, 0.5), (16, 0.0), (16, 0.1), (32, 0.7)] {
+        let voxel_grid = random_grid(&mut rng, size, density);
+        let svo = SparseVoxelOctree::try_from(&voxel_grid).unwrap();
+        assert_eq!(VoxelGrid::from(&svo).data, voxel_grid.data);
+        let rebuilt = SparseVoxelOctree::from_voxels(size, svo.voxels()).unwrap();
+        assert_eq!(rebuilt.node_pool, svo.node_pool);
+    }
+
+    // leaves above the lowest level are expanded into voxels
+    let mut svo = SparseVoxelOctree::try_from(&VoxelGrid::new(8)).unwrap();
+    svo.fill_box(Point3::new(0, 0, 4), Point3::new(4, 8, 8), 5)
+        .unwrap();
+    let voxel_grid = VoxelGrid::from(&svo);
+    assert_eq!(svo.voxels().count(), 4 * 8 * 4);
+    assert!(svo
+        .voxels()
+        .all(|(p, color)| p.x < 4 && p.z >= 4 && color == 5));
+    assert_eq!(voxel_grid.data[3][7][4], Some(5));
+
+    // through a file whose name holds the grid size
+    let dir = std::env::temp_dir();
+    let path = dir.join(format!("svo_roundtrip_{}_8.csv", std::process::id()));
+    let path = path.to_str().unwrap();
+    svo.write_csv(path).unwrap();
+    let read = SparseVoxelOctree::from_csv(path.to_string());
+    std::fs::remove_file(path).unwrap();
+    assert_eq!(VoxelGrid::from(&read.unwrap()).data, voxel_grid.data);
+    for name in &["svo_roundtrip.csv", "svo_roundtrip_16.csv"] {
+        let path = dir.join(name);
+        assert!(svo.write_csv(path.to_str().unwrap()).is_err());
+        assert!(!path.exists());
+    }
+}
+
+// Closest filled voxel of the grid along the ray, found by intersecting the
+// ray with every voxel. Returns the distance in multiples of `dir` and the
+// voxel. Rays that only touch the boundary of a voxel hit it if
+// `touching` is set.
+#[cfg(test)]
+pub(crate) fn brute_force_raycast(
+    voxel_grid: &VoxelGrid,
+    origin: Point3<f32>,
+    dir: Vector3<f32>,
+    touching: bool,
+) -> Option<(f32, Point3<usize>)> {
+    let size = voxel_grid.size;
+    let mut closest: Option<(f32, Point3<usize>)> = None;
+    for (x, plane) in voxel_grid.data.iter().enumerate() {
+        for (y, column) in plane.iter().enumerate() {
+            for (z, voxel) in column.iter().enumerate() {
+                if voxel.is_none() {
+                    continue;
+                }
+                let (mut t_enter, mut t_exit) = (0.0f32, f32::INFINITY);
+                for (i, &v) in [x, y, z].iter().enumerate() {
+                    let lower = v as f32 / size as f32;
+                    let upper = (v + 1) as f32 / size as f32;
+                    if dir[i] == 0.0 {
+                        let inside = if touching {
+                            lower <= origin[i] && origin[i] <= upper
+                        } else {
+                            lower < origin[i] && origin[i] < upper
+                        };
+                        if !inside {
+                            t_exit = -1.0;
+                        }
+                        continue;
+                    }
+                    let t0 = (lower - origin[i]) / dir[i];
+                    let t1 = (upper - origin[i]) / dir[i];
+                    t_enter = t_enter.max(t0.min(t1));
+                    t_exit = t_exit.min(t0.max(t1));
+                }
+                let hit = if touching {
+                    t_enter <= t_exit
+                } else {
+                    t_enter < t_exit
+                };
+                if hit && t_enter < closest.map_or(f32::INFINITY, |(t, _)| t) {
+                    closest = Some((t_enter, Point3::new(x, y, z)));
+                }
+            }
+        }
+    }
+    closest
+}
+
+#[test]
+fn test_degenerate_rays() {
+    use rand::{Rng, SeedableRng};
+    let size = 16;
+    let mut rng = rand::rngs::StdRng::seed_from_u64(21);
+    let voxel_grid = random_grid(&mut rng, size, 0.05);
+    let svo = SparseVoxelOctree::try_from(&voxel_grid).unwrap();
+    let color = |v: Point3<usize>| voxel_grid.data[v.x][v.y][v.z].unwrap();
+    let filled = |p: Point3<f32>| {
+        let v = p.coords * size as f32;
+        (0..3).all(|i| v[i] >= 0.0 && v[i] < size as f32)
+            && voxel_grid.data[v.x as usize][v.y as usize][v.z as usize].is_some()
+    };
+    let axes = [
+        Vector3::new(1.0, 0.0, 0.0),
+        Vector3::new(-1.0, 0.0, 0.0),
+        Vector3::new(0.0, 1.0, 0.0),
+        Vector3::new(0.0, -1.0, 0.0),
+        Vector3::new(0.0, 0.0, 1.0),
+        Vector3::new(0.0, 0.0, -1.0),
+        Vector3::new(-0.0, -0.0, 1.0),
+        Vector3::new(-0.0, 1.0, -0.0),
+    ];
+    let random_point = |rng: &mut rand::rngs::StdRng, lattice: f32| {
+        Point3::from(Vector3::from_fn(|_, _| {
+            (rng.gen_range(-2..size as i32 + 2) as f32 + lattice) / size as f32
+        }))
+    };
+
+    // axis-aligned rays through voxel centres, outside and inside of the grid
+    // and inside of filled voxels, and random rays starting inside the grid
+    for i in 0..3000 {
+        let (origin, dir) = if i % 2 == 0 {
+            (random_point(&mut rng, 0.5), axes[i / 2 % axes.len()])
+        } else {
+            let origin = Point3::new(rng.gen(), rng.gen(), rng.gen());
+            let dir = Vector3::new(
+                rng.gen_range(-1.0..1.0),
+                rng.gen_range(-1.0..1.0),
+                rng.gen_range(-1.0..1.0),
+            );
+            (origin, dir.normalize())
+        };
+        let hit = svo.raycast(origin, dir);
+        match (hit, brute_force_raycast(&voxel_grid, origin, dir, false)) {
+            (None, None) => (),
+            (Some(hit), Some((t, voxel))) => {
+                assert_eq!(hit.color, color(voxel), "ray {} {}", origin, dir);
+                assert!((hit.pos - (origin + t * dir)).norm() < 1e-5);
+                if filled(origin) {
+                    assert_eq!(hit.normal, Vector3::zeros());
+                } else if i % 2 == 0 {
+                    assert_eq!(hit.normal, -dir);
+                }
+            }
+            (hit, expected) => {
+                let pos = hit.map(|hit| hit.pos);
+                panic!("ray {} {}: {:?} {:?}", origin, dir, pos, expected)
+            }
+        }
+    }
+
+    // nearly axis-aligned rays just beside voxel faces, whose tiny direction
+    // components point either way
+    for i in 0..2000 {
+        let mut origin = random_point(&mut rng, 0.5);
+        let mut dir = axes[i % axes.len()];
+        for axis in 0..3 {
+            if dir[axis] == 0.0 {
+                let side = if rng.gen() { 1.0 } else { -1.0 };
+                origin[axis] += side * (0.5 / size as f32 - 2e-5);
+                dir[axis] = rng.gen_range(-1e-4..1e-4);
+            }
+        }
+        let expected = brute_force_raycast(&voxel_grid, origin, dir, false);
+        match (svo.raycast(origin, dir), expected) {
+            (None, None) => (),
+            (Some(hit), Some((_, voxel))) => {
+                // the distance along the ray is only as precise as the
+                // position of the origin divided by the tiny components
+                assert_eq!(hit.color, color(voxel), "ray {} {}", origin, dir);
+                let (min, max) = svo.node_bounds(voxel, 1);
+                assert!(crate::nearest::box_distance(hit.pos, min, max) < 1e-5);
+            }
+            (hit, expected) => {
+                let pos = hit.map(|hit| hit.pos);
+                panic!("ray {} {}: {:?} {:?}", origin, dir, pos, expected)
+            }
+        }
+    }
+
+    // rays along voxel faces and edges may or may not hit the voxels they
+    // touch, but never go further than the first voxel they enter
+    for i in 0..2000 {
+        let mut origin = random_point(&mut rng, 0.5);
+        let dir = axes[i % axes.len()];
+        for axis in 0..3 {
+            if dir[axis] == 0.0 && rng.gen_bool(0.7) {
+                origin[axis] -= 0.5 / size as f32;
+            }
+        }
+        let touching = brute_force_raycast(&voxel_grid, origin, dir, true);
+        let entering = brute_force_raycast(&voxel_grid, origin, dir, false);
+        match svo.raycast(origin, dir) {
+            Some(hit) => {
+                let t = (hit.pos - origin).dot(&dir);
+                let (first, _) = touching.unwrap();
+                let last = entering.map_or(f32::MAX, |(t, _)| t);
+                assert!(
+                    first - 1e-5 <= t && t <= last + 1e-5,
+                    "ray {} {}",
+                    origin,
+                    dir
+                );
+            }
+            None => assert_eq!(entering, None, "ray {} {}", origin, dir),
+        }
+    }
+}
+
+// Describes how the octree raycast differs from the brute-force one, if it
+// does. Rays that graze a voxel may hit it or not, so they never differ.
+#[cfg(test)]
+fn raycast_mismatch(
+    voxel_grid: &VoxelGrid,
+    svo: &SparseVoxelOctree,
+    origin: Point3<f32>,
+    dir: Vector3<f32>,
+) -> Option<String> {
+    let entering = brute_force_raycast(voxel_grid, origin, dir, false);
+    let touching = brute_force_raycast(voxel_grid, origin, dir, true);
+    if touching.map(|(t, _)| t) != entering.map(|(t, _)| t) {
+        return None;
+    }
+    let (hit, (t, voxel)) = match (svo.raycast(origin, dir), entering) {
+        (None, None) => return None,
+        (Some(hit), None) => return Some(format!("hit at {} instead of a miss", hit.pos)),
+        (None, Some((t, _))) => return Some(format!("missed the hit at t = {}", t)),
+        (Some(hit), Some(expected)) => (hit, expected),
+    };
+    let hit_t = (hit.pos - origin).dot(&dir) / dir.norm_squared();
+    if (hit_t - t).abs() > 1e-4 {
+        return Some(format!("hit at t = {} instead of {}", hit_t, t));
+    }
+    let color = voxel_grid.data[voxel.x][voxel.y][voxel.z].unwrap();
+    if hit.color != color {
+        return Some(format!("hit colour {} instead of {}", hit.color, color));
+    }
+    // The normal belongs to the face through which the voxel is entered. It
+    // is zero if the ray starts inside, and either face's on an edge.
+    let size = voxel_grid.size as f32;
+    let entry = Vector3::from_fn(|i, _| {
+        let t0 = (voxel[i] as f32 / size - origin[i]) / dir[i];
+        let t1 = ((voxel[i] + 1) as f32 / size - origin[i]) / dir[i];
+        t0.min(t1)
+    });
+    let faces: Vec<_> = (0..3)
+        .filter(|&i| t > 0.0 && dir[i] != 0.0 && (entry[i] - t).abs() < 1e-6)
+        .collect();
+    let expected = match faces[..] {
+        [] => Vector3::zeros(),
+        [axis] => {
+            let mut normal = Vector3::zeros();
+            normal[axis] = -dir[axis].signum();
+            normal
+        }
+        _ => return None,
+    };
+    if hit.normal != expected {
+        return Some(format!("normal {} instead of {}", hit.normal, expected));
+    }
+    None
+}
+
+// A grid and a ray of the randomized raycast tests.
+#[cfg(test)]
+#[derive(Clone, Debug)]
+struct RaycastCase {
+    size: usize,
+    voxels: Vec<(Point3<usize>, u32)>,
+    origin: Point3<f32>,
+    dir: Vector3<f32>,
+}
+
+#[cfg(test)]
+impl RaycastCase {
+    fn mismatch(&self) -> Option<String> {
+        let mut voxel_grid = VoxelGrid::new(self.size);
+        for &(p, color) in &self.voxels {
+            voxel_grid.data[p.x][p.y][p.z] = Some(color);
+        }
+        let svo = SparseVoxelOctree::try_from(&voxel_grid).unwrap();
+        raycast_mismatch(&voxel_grid, &svo, self.origin, self.dir)
+    }
+
+    // Simplifies the case for as long as it keeps failing: drops voxels,
+    // crops the grid to an octant and rounds the ray.
+    fn shrink(mut self, fails: &dyn Fn(&RaycastCase) -> bool) -> RaycastCase {
+        loop {
+            match self.simpler_cases().into_iter().find(|case| fails(case)) {
+                Some(case) => self = case,
+                None => return self,
+            }
+        }
+    }
+
+    fn simpler_cases(&self) -> Vec<RaycastCase> {
+        let mut cases = Vec::new();
+        // The octant of the grid that holds all voxels, scaled up together
+        // with the ray.
+        let half = self.size / 2;
+        for octant in 0..8 {
+            let offset = Vector3::new(octant & 1, (octant >> 1) & 1, octant >> 2) * half;
+            let inside = |p: Point3<usize>| (0..3).all(|i| p[i] / half == offset[i] / half);
+            if half < 2 || !self.voxels.iter().all(|&(p, _)| inside(p)) {
+                continue;
+            }
+            let shift = offset.map(|v| v as f32 / self.size as f32);
+            cases.push(RaycastCase {
+                size: half,
+                voxels: self.voxels.iter().map(|&(p, c)| (p - offset, c)).collect(),
+                origin: Point3::from((self.origin - shift).coords * 2.0),
+                dir: self.dir,
+            });
+        }
+        for i in 0..self.voxels.len() {
+            let mut case = self.clone();
+            case.voxels.remove(i);
+            cases.push(case);
+        }
+        // Rays on coarser grids, starting with the coarsest. A rounded value
+        // stays the same on all finer grids, so this cannot go on forever.
+        for &scale in &[1.0, 4.0, 16.0, 64.0, 256.0] {
+            let round = |v: f32| (v * scale).round() / scale;
+            let mut case = self.clone();
+            case.origin = Point3::from(self.origin.coords.map(round));
+            case.dir = self.dir.map(round);
+            if (case.origin, case.dir) != (self.origin, self.dir) && case.dir != Vector3::zeros() {
+                cases.push(case);
+            }
+        }
+        cases
+    }
+}
+
+#[test]
+fn test_random_raycasts() {
+    use rand::{Rng, SeedableRng};
+    let mut rng = rand::rngs::StdRng::seed_from_u64(23);
+    for _ in 0..20 {
+        let size = 1 << rng.gen_range(1..=4);
+        let density = rng.gen_range(0.0..0.3);
+        let voxel_grid = random_grid(&mut rng, size, density);
+        let svo = SparseVoxelOctree::try_from(&voxel_grid).unwrap();
+        let voxels: Vec<_> = svo.voxels().collect();
+        for i in 0..250 {
+            let origin = Point3::new(
+                rng.gen_range(-0.5..1.5),
+                rng.gen_range(-0.5..1.5),
+                rng.gen_range(-0.5..1.5),
+            );
+            // directions of any length, some of them along an axis
+            let length = rng.gen_range(0.1..10.0);
+            let dir = if i % 4 == 0 {
+                let mut dir = Vector3::zeros();
+                dir[rng.gen_range(0..3)] = if rng.gen() { length } else { -length };
+                dir
+            } else {
+                let target = Point3::new(rng.gen(), rng.gen(), rng.gen());
+                (target - origin).normalize() * length
+            };
+            if raycast_mismatch(&voxel_grid, &svo, origin, dir).is_some() {
+                let case = RaycastCase {
+                    size,
+                    voxels: voxels.clone(),
+                    origin,
+                    dir,
+                };
+                let case = case.shrink(&|case| case.mismatch().is_some());
+                panic!("{:?}: {}", case, case.mismatch().unwrap());
+            }
+        }
+    }
+}
+
+#[test]
+fn test_shrink_raycast_case() {
+    use rand::SeedableRng;
+    let mut rng = rand::rngs::StdRng::seed_from_u64(23);
+    let voxels: Vec<_> = random_voxels(&mut rng, 16, 50);
+    // a ray through the centre of one of the voxels
+    let origin = Point3::new(-0.3127, 0.4519, 0.5023);
+    let target = Point3::from(voxels[0].0.coords.map(|v| (v as f32 + 0.5) / 16.0));
+    let case = RaycastCase {
+        size: 16,
+        voxels,
+        origin,
+        dir: target - origin,
+    };
+    // pretend that every hit is wrong
+    let hits = |case: &RaycastCase| {
+        let mut voxel_grid = VoxelGrid::new(case.size);
+        for &(p, color) in &case.voxels {
+            voxel_grid.data[p.x][p.y][p.z] = Some(color);
+        }
+        brute_force_raycast(&voxel_grid, case.origin, case.dir, false).is_some()
+    };
+    assert!(hits(&case));
+    let shrunk = case.shrink(&hits);
+    assert_eq!((shrunk.size, shrunk.voxels.len()), (2, 1));
+    assert!(hits(&shrunk));
+}
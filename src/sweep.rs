@@ -0,0 +1,356 @@
+use na::{base::Vector3, geometry::Point3};
+
+use crate::nearest::box_distance;
+use crate::svo::{is_empty, is_leaf, SparseVoxelOctree};
+use crate::traverse::leaf_voxel;
+
+/// First contact of a shape that is moved through an octree.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct SweepHit {
+    /// Fraction of the motion at the time of impact, in [0, 1].
+    pub time: f32,
+    /// Normal of the voxel surface at the contact, pointing towards the shape.
+    /// It is zero if the shape overlaps filled voxels from the start.
+    pub normal: Vector3<f32>,
+    /// The filled voxel that is touched.
+    pub voxel: Point3<usize>,
+    pub color: u32,
+}
+
+enum Shape {
+    Sphere(f32),
+    // half extents
+    Box(Vector3<f32>),
+}
+
+// A shape that moves from `center` to `center + motion`.
+struct Sweep {
+    shape: Shape,
+    center: Point3<f32>,
+    motion: Vector3<f32>,
+}
+
+// Entry and exit of the ray `o + t * d` through the box, and the axis of the
+// face it enters through.
+fn ray_box(
+    o: Point3<f32>,
+    d: Vector3<f32>,
+    min: Point3<f32>,
+    max: Point3<f32>,
+) -> Option<(f32, f32, usize)> {
+    let (mut t_enter, mut t_exit, mut axis) = (f32::NEG_INFINITY, f32::INFINITY, 0);
+    for i in 0..3 {
+        if d[i] == 0.0 {
+            if o[i] < min[i] || o[i] > max[i] {
+                return None;
+            }
+            continue;
+        }
+        let (t0, t1) = ((min[i] - o[i]) / d[i], (max[i] - o[i]) / d[i]);
+        if t0.min(t1) > t_enter {
+            t_enter = t0.min(t1);
+            axis = i;
+        }
+        t_exit = t_exit.min(t0.max(t1));
+    }
+    if t_enter > t_exit {
+        None
+    } else {
+        Some((t_enter, t_exit, axis))
+    }
+}
+
+// Smaller root of a * t^2 + b * t + c, if any.
+fn first_root(a: f32, b: f32, c: f32) -> Option<f32> {
+    let discriminant = b * b - 4.0 * a * c;
+    if a == 0.0 || discriminant < 0.0 {
+        return None;
+    }
+    Some((-b - discriminant.sqrt()) / (2.0 * a))
+}
+
+impl Sweep {
+    // Half extents of the box around the shape.
+    fn extent(&self) -> Vector3<f32> {
+        match self.shape {
+            Shape::Sphere(radius) => Vector3::repeat(radius),
+            Shape::Box(half_size) => half_size,
+        }
+    }
+
+    // Time at which the shape enters the box around a cube that is expanded by
+    // the extent of the shape, which is never later than the time of impact
+    // with anything inside of the cube.
+    fn entry_time(&self, min: Point3<f32>, max: Point3<f32>) -> Option<f32> {
+        let extent = self.extent();
+        let (t_enter, t_exit, _) = ray_box(self.center, self.motion, min - extent, max + extent)?;
+        if t_exit < 0.0 || t_enter > 1.0 {
+            None
+        } else {
+            // -0.0 would be ordered after all other times
+            Some(if t_enter > 0.0 { t_enter } else { 0.0 })
+        }
+    }
+
+    // Exact time of impact with a filled cube and the normal at the contact.
+    fn time_of_impact(&self, min: Point3<f32>, max: Point3<f32>) -> Option<(f32, Vector3<f32>)> {
+        let (o, d) = (self.center, self.motion);
+        match self.shape {
+            Shape::Box(half_size) => {
+                let (t_enter, t_exit, axis) = ray_box(o, d, min - half_size, max + half_size)?;
+                if t_enter < 0.0 && t_exit > 0.0 {
+                    return Some((0.0, Vector3::zeros()));
+                }
+                if !(0.0..=1.0).contains(&t_enter) {
+                    return None;
+                }
+                let mut normal = Vector3::zeros();
+                normal[axis] = -d[axis].signum();
+                Some((if t_enter > 0.0 { t_enter } else { 0.0 }, normal))
+            }
+            Shape::Sphere(radius) => {
+                if box_distance(o, min, max) < radius {
+                    return Some((0.0, Vector3::zeros()));
+                }
+                // The cube expanded by the sphere is the union of the cube
+                // grown along each axis, a cylinder around each edge and a
+                // sphere around each corner.
+                let mut times = Vec::new();
+                for axis in 0..3 {
+                    let mut grow = Vector3::zeros();
+                    grow[axis] = radius;
+                    if let Some((t, _, _)) = ray_box(o, d, min - grow, max + grow) {
+                        times.push(t);
+                    }
+                    let (b, c) = ((axis + 1) % 3, (axis + 2) % 3);
+                    for &(edge_b, edge_c) in &[
+                        (min[b], min[c]),
+                        (min[b], max[c]),
+                        (max[b], min[c]),
+                        (max[b], max[c]),
+                    ] {
+                        let (ob, oc) = (o[b] - edge_b, o[c] - edge_c);
+                        let a = d[b] * d[b] + d[c] * d[c];
+                        let root = first_root(
+                            a,
+                            2.0 * (ob * d[b] + oc * d[c]),
+                            ob * ob + oc * oc - radius * radius,
+                        );
+                        if let Some(t) = root {
+                            let along = o[axis] + t * d[axis];
+                            if min[axis] <= along && along <= max[axis] {
+                                times.push(t);
+                            }
+                        }
+                    }
+                }
+                for corner in 0..8 {
+                    let corner = Point3::new(
+                        if corner & 1 == 0 { min.x } else { max.x },
+                        if corner & 2 == 0 { min.y } else { max.y },
+                        if corner & 4 == 0 { min.z } else { max.z },
+                    );
+                    let oc = o - corner;
+                    let root =
+                        first_root(d.dot(&d), 2.0 * oc.dot(&d), oc.dot(&oc) - radius * radius);
+                    times.extend(root);
+                }
+                let t = times
+                    .into_iter()
+                    .filter(|t| (0.0..=1.0).contains(t))
+                    .fold(f32::MAX, f32::min);
+                if t == f32::MAX {
+                    return None;
+                }
+                let center = o + t * d;
+                let contact = self.contact(t, min, max);
+                Some((
+                    if t > 0.0 { t } else { 0.0 },
+                    (center - contact).normalize(),
+                ))
+            }
+        }
+    }
+
+    // Point of the cube that is touched at time t.
+    fn contact(&self, t: f32, min: Point3<f32>, max: Point3<f32>) -> Point3<f32> {
+        let center = self.center + t * self.motion;
+        Point3::from(Vector3::from_fn(|i, _| center[i].max(min[i]).min(max[i])))
+    }
+}
+
+impl SparseVoxelOctree {
+    // Traverses the nodes in the order in which the expanded boxes around them
+    // are entered. Subtrees that are entered after the earliest impact found
+    // so far are skipped.
+    fn sweep(&self, sweep: &Sweep) -> Option<SweepHit> {
+        // time of impact, normal and the leaf that is hit first
+        let mut best_time = f32::INFINITY;
+        let mut best = None;
+        self.best_first(
+            |node, min, size| {
+                if is_empty(node) {
+                    return None;
+                }
+                let (lower, upper) = self.node_bounds(min, size);
+                sweep.entry_time(lower, upper)
+            },
+            |node, min, depth, t| {
+                if t >= best_time {
+                    return false;
+                }
+                if is_leaf(node) {
+                    let size = self.size >> depth;
+                    let (lower, upper) = self.node_bounds(min, size);
+                    if let Some((t, normal)) = sweep.time_of_impact(lower, upper) {
+                        if t < best_time {
+                            best_time = t;
+                            best = Some((normal, node[1], min, size));
+                        }
+                    }
+                }
+                true
+            },
+        );
+
+        let (normal, color, min, size) = best?;
+        let time = best_time;
+        let (lower, upper) = self.node_bounds(min, size);
+        let contact = sweep.contact(time, lower, upper);
+        Some(SweepHit {
+            time,
+            normal,
+            voxel: leaf_voxel(contact, min, size, self.size),
+            color,
+        })
+    }
+
+    /// Moves a sphere from `center` to `center + motion` and returns its
+    /// first contact with a filled voxel.
+    pub fn sweep_sphere(
+        &self,
+        center: Point3<f32>,
+        radius: f32,
+        motion: Vector3<f32>,
+    ) -> Option<SweepHit> {
+        self.sweep(&Sweep {
+            shape: Shape::Sphere(radius),
+            center,
+            motion,
+        })
+    }
+
+    /// Moves the box `min..max` by `motion` and returns its first contact with
+    /// a filled voxel.
+    pub fn sweep_box(
+        &self,
+        min: Point3<f32>,
+        max: Point3<f32>,
+        motion: Vector3<f32>,
+    ) -> Option<SweepHit> {
+        self.sweep(&Sweep {
+            shape: Shape::Box((max - min) / 2.0),
+            center: na::center(&min, &max),
+            motion,
+        })
+    }
+}
+
+#[test]
+fn test_sweep() {
+    use crate::svo::random_voxels;
+    use rand::{Rng, SeedableRng};
+
+    let size = 16;
+    let mut rng = rand::rngs::StdRng::seed_from_u64(20);
+    let voxels = random_voxels(&mut rng, size, 60);
+    let mut svo = SparseVoxelOctree::from_voxels(size, voxels).unwrap();
+    svo.fill_box(Point3::new(0, 0, 0), Point3::new(8, 8, 2), 2)
+        .unwrap();
+    let boxes: Vec<_> = svo
+        .leaves()
+        .map(|leaf| svo.node_bounds(leaf.min, leaf.size))
+        .collect();
+
+    // Steps through the motion and returns the first time at which the shape
+    // touches a filled voxel.
+    let sampled_time = |touches: &dyn Fn(Point3<f32>, Point3<f32>, Point3<f32>) -> bool,
+                        center: Point3<f32>,
+                        motion: Vector3<f32>| {
+        (0..=500).map(|i| i as f32 / 500.0).find(|&t| {
+            boxes
+                .iter()
+                .any(|&(min, max)| touches(center + t * motion, min, max))
+        })
+    };
+    for i in 0..150 {
+        let center = Point3::new(
+            rng.gen_range(-0.2..1.2),
+            rng.gen_range(-0.2..1.2),
+            rng.gen_range(-0.2..1.2),
+        );
+        let motion = Vector3::new(
+            rng.gen_range(-1.0..1.0),
+            rng.gen_range(-1.0..1.0),
+            rng.gen_range(-1.0..1.0),
+        );
+        let extent = rng.gen_range(0.01..0.1);
+        let (hit, expected) = if i % 2 == 0 {
+            let touches = |c, min, max| box_distance(c, min, max) <= extent;
+            let hit = svo.sweep_sphere(center, extent, motion);
+            (hit, sampled_time(&touches, center, motion))
+        } else {
+            let half = Vector3::repeat(extent);
+            let touches = |c: Point3<f32>, min: Point3<f32>, max: Point3<f32>| {
+                (0..3).all(|i| c[i] + half[i] >= min[i] && c[i] - half[i] <= max[i])
+            };
+            let hit = svo.sweep_box(center - half, center + half, motion);
+            (hit, sampled_time(&touches, center, motion))
+        };
+        match (hit, expected) {
+            (None, None) => (),
+            (Some(hit), Some(expected)) => {
+                assert!(hit.time <= expected + 1e-4 && hit.time > expected - 3e-3);
+                assert!(svo.contains(hit.voxel.x, hit.voxel.y, hit.voxel.z));
+                if hit.time > 0.0 {
+                    assert!((hit.normal.norm() - 1.0).abs() < 1e-4);
+                    assert!(hit.normal.dot(&motion) <= 0.0);
+                }
+            }
+            // grazing contacts may fall between the samples
+            (Some(hit), None) => assert!(hit.time > 0.0),
+            (None, Some(_)) => panic!("missed impact from {} by {}", center, motion),
+        }
+    }
+
+    // a sphere dropped onto a filled slab at the bottom
+    let mut svo = SparseVoxelOctree::from_voxels(size, Vec::new()).unwrap();
+    svo.fill_box(Point3::new(0, 0, 0), Point3::new(8, 8, 2), 2)
+        .unwrap();
+    let center = Point3::new(0.2, 0.2, 0.5);
+    let hit = svo
+        .sweep_sphere(center, 0.05, Vector3::new(0.0, 0.0, -1.0))
+        .unwrap();
+    assert!((hit.time - (0.5 - 0.125 - 0.05)).abs() < 1e-5);
+    assert_eq!(hit.normal, Vector3::new(0.0, 0.0, 1.0));
+    assert_eq!((hit.voxel, hit.color), (Point3::new(3, 3, 1), 2));
+    // shapes that overlap voxels from the start hit them immediately
+    let hit = svo
+        .sweep_box(
+            Point3::new(0.1, 0.1, 0.1),
+            Point3::new(0.2, 0.2, 0.2),
+            Vector3::x(),
+        )
+        .unwrap();
+    assert_eq!((hit.time, hit.normal), (0.0, Vector3::zeros()));
+    // shapes that touch voxels from the start hit them at a time of +0
+    let hit = svo
+        .sweep_box(
+            Point3::new(0.5, 0.1, 0.05),
+            Point3::new(0.6, 0.2, 0.1),
+            -Vector3::x(),
+        )
+        .unwrap();
+    assert_eq!(hit.time, 0.0);
+    assert!(hit.time.is_sign_positive());
+}
@@ -0,0 +1,213 @@
+use std::collections::HashMap;
+use std::convert::TryFrom;
+
+use crate::raycast::{RayQuery, RaycastHit, Raycastable};
+use crate::svo::{
+    child_tile as svo_child_tile, is_empty, is_leaf, raycast_octree, BuildError, SparseVoxelOctree,
+    Traversable,
+};
+
+// Same layout as `SVONode`, except that interior nodes carry mirror flags:
+// node[0] = empty (bit 31) | leaf (bit 30) | mirror x, y, z (bits 27..29) | child tile index.
+// node[1] = colour.
+type DagNode = [u32; 2];
+
+const EMPTY_NODE: DagNode = [1 << 31, 0];
+
+fn leaf_node(color: u32) -> DagNode {
+    [1 << 30, color]
+}
+
+const CHILD_MASK: u32 = (1 << 27) - 1;
+
+// `node` is the index of the node in the octree, which is reported if the
+// child tile index does not fit.
+fn interior_node(
+    node: usize,
+    children: usize,
+    mirror: u32,
+    color: u32,
+) -> Result<DagNode, BuildError> {
+    if children > CHILD_MASK as usize {
+        return Err(BuildError::PointerOutOfRange { node, children });
+    }
+    Ok([(mirror << 27) | children as u32, color])
+}
+
+fn mirror_flags(node: &DagNode) -> u32 {
+    (node[0] >> 27) & 7
+}
+
+fn child_tile(node: &DagNode) -> usize {
+    (node[0] & CHILD_MASK) as usize
+}
+
+// Mirrors a tile along the axes set in `mirror`. Child slot bits correspond to
+// the axes, so the children swap places and their subtrees are mirrored too.
+fn reflect(tile: &[DagNode; 8], mirror: u32) -> [DagNode; 8] {
+    let mut reflected = [EMPTY_NODE; 8];
+    for (slot, node) in reflected.iter_mut().enumerate() {
+        *node = tile[slot ^ mirror as usize];
+        if !is_empty(node) && !is_leaf(node) {
+            node[0] ^= mirror << 27;
+        }
+    }
+    reflected
+}
+
+/// Sparse voxel DAG that, beyond identical subtrees, also stores subtrees that
+/// are mirror images of each other only once. Child pointers carry flags that
+/// mirror the referenced subtree along x, y and z, which are applied while
+/// traversing.
+pub struct SymmetricVoxelDag {
+    pub node_pool: Vec<DagNode>,
+    pub size: usize,
+}
+
+struct SymmetricDagBuilder<'a> {
+    svo: &'a SparseVoxelOctree,
+    node_pool: Vec<DagNode>,
+    // canonical tile -> tile index in node_pool
+    unique_tiles: HashMap<[DagNode; 8], usize>,
+    // tile index in the octree -> tile index and mirror flags
+    merged_tiles: HashMap<usize, (usize, u32)>,
+}
+
+impl SymmetricDagBuilder<'_> {
+    // Returns the index of the canonical tile for the subtree below the octree
+    // tile at `tile_idx`, and the flags that mirror it into the original.
+    fn merge_tile(&mut self, tile_idx: usize) -> Result<(usize, u32), BuildError> {
+        if let Some(&merged) = self.merged_tiles.get(&tile_idx) {
+            return Ok(merged);
+        }
+        let mut tile = [EMPTY_NODE; 8];
+        for (slot, node) in tile.iter_mut().enumerate() {
+            let svo_node = self.svo.node_pool[tile_idx + slot];
+            if is_empty(&svo_node) {
+                continue;
+            }
+            *node = if is_leaf(&svo_node) {
+                leaf_node(svo_node[1])
+            } else {
+                let (children, mirror) =
+                    self.merge_tile(svo_child_tile(&self.svo.node_pool, tile_idx + slot))?;
+                interior_node(tile_idx + slot, children, mirror, svo_node[1])?
+            };
+        }
+
+        // The smallest of the eight reflections is the canonical tile.
+        let (mirror, canonical) = (0..8)
+            .map(|mirror| (mirror, reflect(&tile, mirror)))
+            .min_by_key(|(_, reflected)| *reflected)
+            .unwrap();
+        let node_pool = &mut self.node_pool;
+        let canonical_idx = *self.unique_tiles.entry(canonical).or_insert_with(|| {
+            node_pool.extend_from_slice(&canonical);
+            node_pool.len() - 8
+        });
+        self.merged_tiles.insert(tile_idx, (canonical_idx, mirror));
+        Ok((canonical_idx, mirror))
+    }
+}
+
+// Fails if a child tile index does not fit into the 27 bits left next to the
+// mirror flags.
+impl TryFrom<&SparseVoxelOctree> for SymmetricVoxelDag {
+    type Error = BuildError;
+
+    fn try_from(svo: &SparseVoxelOctree) -> Result<SymmetricVoxelDag, BuildError> {
+        let mut builder = SymmetricDagBuilder {
+            svo,
+            node_pool: vec![EMPTY_NODE],
+            unique_tiles: HashMap::new(),
+            merged_tiles: HashMap::new(),
+        };
+        let root = svo.node_pool[0];
+        let (root_tile_idx, mirror) = builder.merge_tile(svo_child_tile(&svo.node_pool, 0))?;
+        let mut node_pool = builder.node_pool;
+        node_pool[0] = interior_node(0, root_tile_idx, mirror, root[1])?;
+        Ok(SymmetricVoxelDag {
+            node_pool,
+            size: svo.size,
+        })
+    }
+}
+
+impl SymmetricVoxelDag {
+    pub fn size_bytes(&self) -> usize {
+        std::mem::size_of::<DagNode>() * self.node_pool.len()
+    }
+}
+
+// Nodes are referenced by their index and the accumulated mirror flags of the
+// path from the root, which have to be applied to their child tile.
+impl Traversable for SymmetricVoxelDag {
+    type Ref = (usize, u32);
+
+    fn root(&self) -> (usize, u32) {
+        (0, mirror_flags(&self.node_pool[0]))
+    }
+
+    fn child(&self, (node, mirror): (usize, u32), slot: u32) -> Option<(usize, u32)> {
+        let child = child_tile(&self.node_pool[node]) + (slot ^ mirror) as usize;
+        let child_node = &self.node_pool[child];
+        if is_empty(child_node) {
+            None
+        } else {
+            Some((child, mirror ^ mirror_flags(child_node)))
+        }
+    }
+
+    fn is_leaf(&self, (node, _): (usize, u32)) -> bool {
+        is_leaf(&self.node_pool[node])
+    }
+
+    fn color(&self, (node, _): (usize, u32)) -> u32 {
+        self.node_pool[node][1]
+    }
+
+    fn index(&self, (node, _): (usize, u32)) -> usize {
+        node
+    }
+
+    fn size(&self) -> usize {
+        self.size
+    }
+}
+
+impl Raycastable for SymmetricVoxelDag {
+    fn raycast_query(&self, query: &RayQuery) -> Option<RaycastHit> {
+        raycast_octree(self, query)
+    }
+}
+
+#[test]
+fn test_symmetric_dag() {
+    use crate::svo::{assert_same_raycasts, random_voxels};
+    use na::geometry::Point3;
+    use rand::SeedableRng;
+
+    // a random shape in one octant, mirrored into all others
+    let mut rng = rand::rngs::StdRng::seed_from_u64(8);
+    let mut voxels = Vec::new();
+    for (p, color) in random_voxels(&mut rng, 16, 200) {
+        let (x, y, z) = (p.x, p.y, p.z);
+        for mirror in 0..8 {
+            let mx = if mirror & 1 != 0 { 31 - x } else { x };
+            let my = if mirror & 2 != 0 { 31 - y } else { y };
+            let mz = if mirror & 4 != 0 { 31 - z } else { z };
+            voxels.push((Point3::new(mx, my, mz), color));
+        }
+    }
+    let svo = SparseVoxelOctree::from_voxels(32, voxels).unwrap();
+    let mut dag = svo.clone();
+    dag.compress_dag().unwrap();
+    let symmetric_dag = SymmetricVoxelDag::try_from(&svo).unwrap();
+    assert!(symmetric_dag.size_bytes() * 4 < dag.size_bytes());
+    assert_same_raycasts(&svo, &symmetric_dag, 8);
+
+    // without any symmetry, the traversal must still match
+    let voxels = random_voxels(&mut rng, 32, 500);
+    let svo = SparseVoxelOctree::from_voxels(32, voxels).unwrap();
+    assert_same_raycasts(&svo, &SymmetricVoxelDag::try_from(&svo).unwrap(), 9);
+}
@@ -0,0 +1,217 @@
+use na::{base::Vector3, geometry::Point3};
+use std::cmp::Reverse;
+use std::collections::BinaryHeap;
+
+use crate::svo::{child_tile, is_empty, is_leaf, SVONode, SparseVoxelOctree};
+
+/// A filled node of an octree. It covers the cube of `size` voxels along each
+/// axis starting at `min`, where `size` is the grid size divided by
+/// `2^depth`. Leaves above the lowest level are filled cubes, interior nodes
+/// have the filtered colour of their children.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct NodeInfo {
+    pub min: Point3<usize>,
+    pub size: usize,
+    pub depth: usize,
+    pub color: u32,
+    pub is_leaf: bool,
+}
+
+// Offset of the child with the given morton digit, whose bits are set for the
+// upper half of each axis. Child slots are ordered with the upper half of each
+// axis first, so this child is in slot `7 ^ digit`. Builders, edits and
+// conversions all write subtrees in morton digit order, which keeps their node
+// pools comparable.
+pub(crate) fn child_offset(digit: usize, half_size: usize) -> Vector3<usize> {
+    Vector3::new(digit & 1, (digit >> 1) & 1, digit >> 2) * half_size
+}
+
+// Voxel of the cube of `size` voxels at `min` that is closest to the world
+// space position `p`, for leaves that cover more than one voxel.
+pub(crate) fn leaf_voxel(
+    p: Point3<f32>,
+    min: Point3<usize>,
+    size: usize,
+    grid_size: usize,
+) -> Point3<usize> {
+    Point3::from(Vector3::from_fn(|i, _| {
+        let v = (p[i] * grid_size as f32).floor();
+        v.max(min[i] as f32).min((min[i] + size - 1) as f32) as usize
+    }))
+}
+
+/// Iterator over the filled leaves of an octree in morton order.
+pub struct Leaves<'a> {
+    svo: &'a SparseVoxelOctree,
+    // tile index, min corner of the tile, depth of its nodes and the next
+    // morton digit
+    stack: Vec<(usize, Point3<usize>, usize, usize)>,
+}
+
+impl Iterator for Leaves<'_> {
+    type Item = NodeInfo;
+
+    fn next(&mut self) -> Option<NodeInfo> {
+        loop {
+            let (tile_idx, min, depth, digit) = self.stack.last_mut()?;
+            if *digit == 8 {
+                self.stack.pop();
+                continue;
+            }
+            let node_idx = *tile_idx + (7 ^ *digit);
+            let (node_depth, size) = (*depth, self.svo.size >> *depth);
+            let node_min = *min + child_offset(*digit, size);
+            *digit += 1;
+
+            let node = self.svo.node_pool[node_idx];
+            if is_empty(&node) {
+                continue;
+            }
+            if is_leaf(&node) {
+                return Some(NodeInfo {
+                    min: node_min,
+                    size,
+                    depth: node_depth,
+                    color: node[1],
+                    is_leaf: true,
+                });
+            }
+            let child_tile_idx = child_tile(&self.svo.node_pool, node_idx);
+            self.stack
+                .push((child_tile_idx, node_min, node_depth + 1, 0));
+        }
+    }
+}
+
+impl SparseVoxelOctree {
+    /// Returns all filled leaves in morton order.
+    pub fn leaves(&self) -> Leaves<'_> {
+        Leaves {
+            svo: self,
+            stack: vec![(child_tile(&self.node_pool, 0), Point3::origin(), 1, 0)],
+        }
+    }
+
+    /// Calls `visitor` for every filled node in morton order, starting with
+    /// the root. The children of a node are skipped if it returns false.
+    pub fn visit<F>(&self, mut visitor: F)
+    where
+        F: FnMut(&NodeInfo) -> bool,
+    {
+        self.visit_node(0, Point3::origin(), 0, &mut visitor);
+    }
+
+    fn visit_node<F>(&self, node_idx: usize, min: Point3<usize>, depth: usize, visitor: &mut F)
+    where
+        F: FnMut(&NodeInfo) -> bool,
+    {
+        let node = self.node_pool[node_idx];
+        let info = NodeInfo {
+            min,
+            size: self.size >> depth,
+            depth,
+            color: node[1],
+            is_leaf: is_leaf(&node),
+        };
+        if !visitor(&info) || info.is_leaf {
+            return;
+        }
+        let tile_idx = child_tile(&self.node_pool, node_idx);
+        for digit in 0..8 {
+            let child_idx = tile_idx + (7 ^ digit);
+            if !is_empty(&self.node_pool[child_idx]) {
+                let child_min = min + child_offset(digit, info.size / 2);
+                self.visit_node(child_idx, child_min, depth + 1, visitor);
+            }
+        }
+    }
+
+    // Visits nodes in increasing order of `key`, e.g. a distance or a time of
+    // impact, starting with the root. `key` gets a node with its min corner and
+    // size, and returns `None` to skip its subtree. `visit` gets a node with
+    // its min corner, depth and key, and returns false to stop. The children
+    // of interior nodes are queued after their parent is visited.
+    pub(crate) fn best_first<K, V>(&self, mut key: K, mut visit: V)
+    where
+        K: FnMut(&SVONode, Point3<usize>, usize) -> Option<f32>,
+        V: FnMut(&SVONode, Point3<usize>, usize, f32) -> bool,
+    {
+        // Keys are not negative, so their bits sort like the floats.
+        let mut queue = BinaryHeap::new();
+        if let Some(k) = key(&self.node_pool[0], Point3::origin(), self.size) {
+            queue.push(Reverse((k.to_bits(), 0, 0, [0, 0, 0])));
+        }
+        while let Some(Reverse((k, node_idx, depth, min))) = queue.pop() {
+            let node = self.node_pool[node_idx];
+            let min = Point3::from(min);
+            if !visit(&node, min, depth, f32::from_bits(k)) {
+                break;
+            }
+            if is_empty(&node) || is_leaf(&node) {
+                continue;
+            }
+            let tile_idx = child_tile(&self.node_pool, node_idx);
+            let half_size = (self.size >> depth) / 2;
+            for digit in 0..8 {
+                let child_idx = tile_idx + (7 ^ digit);
+                let child_min = min + child_offset(digit, half_size);
+                if let Some(k) = key(&self.node_pool[child_idx], child_min, half_size) {
+                    let entry = (k.to_bits(), child_idx, depth + 1, child_min.coords.into());
+                    queue.push(Reverse(entry));
+                }
+            }
+        }
+    }
+}
+
+#[test]
+fn test_leaves() {
+    use crate::morton;
+    use crate::svo::random_voxels;
+    use rand::SeedableRng;
+
+    let mut rng = rand::rngs::StdRng::seed_from_u64(15);
+    let mut voxels = random_voxels(&mut rng, 32, 500);
+    let mut svo = SparseVoxelOctree::from_voxels(32, voxels.clone()).unwrap();
+    let code = |p: Point3<usize>| morton::encode_3d(p.x as u64, p.y as u64, p.z as u64);
+    voxels.sort_by_key(|&(p, _)| code(p));
+    voxels.dedup_by_key(|&mut (p, _)| code(p));
+    let leaves: Vec<_> = svo.leaves().collect();
+    assert_eq!(leaves.len(), voxels.len());
+    for (leaf, &(p, color)) in leaves.iter().zip(&voxels) {
+        assert_eq!((leaf.min, leaf.size, leaf.depth), (p, 1, 5));
+        assert_eq!(leaf.color, color);
+        assert!(leaf.is_leaf);
+    }
+
+    // a filled octant is a single coarse leaf
+    svo.fill_box(Point3::new(16, 0, 0), Point3::new(32, 16, 16), 7)
+        .unwrap();
+    let coarse: Vec<_> = svo.leaves().filter(|leaf| leaf.size > 1).collect();
+    let octant = NodeInfo {
+        min: Point3::new(16, 0, 0),
+        size: 16,
+        depth: 1,
+        color: 7,
+        is_leaf: true,
+    };
+    assert_eq!(coarse, vec![octant]);
+
+    // the visitor sees the same leaves, interior nodes before their children
+    let mut visited = Vec::new();
+    svo.visit(|node| {
+        if node.is_leaf {
+            visited.push(*node);
+        }
+        true
+    });
+    assert_eq!(visited, svo.leaves().collect::<Vec<_>>());
+
+    // pruning below the octants only visits the root and the octants
+    let mut depths = Vec::new();
+    svo.visit(|node| {
+        depths.push(node.depth);
+        node.depth < 1
+    });
+    assert_eq!(depths, vec![0, 1, 1, 1, 1, 1, 1, 1, 1]);
+}
@@ -5,5 +5,5 @@ pub fn clamp<T: std::cmp::PartialOrd>(x: T, min: T, max: T) -> T {
     if x < min {
         return min;
     }
-    return x;
+    x
 }
@@ -1,16 +1,117 @@
-use na::geometry::Point3;
+use na::{base::Vector3, geometry::Point3};
+use std::convert::TryFrom;
+use std::io::{Read, Write};
+
+use crate::raycast::{RayQuery, RaycastHit, Raycastable};
+
+/// Colour of voxels that are loaded without colour columns (opaque white).
+pub const DEFAULT_COLOR: u32 = 0xFFFFFFFF;
+
+/// Packs a colour the way it is stored in the octree: red in the lowest byte,
+/// alpha in the highest.
+pub fn rgba(r: u8, g: u8, b: u8, a: u8) -> u32 {
+    r as u32 | (g as u32) << 8 | (b as u32) << 16 | (a as u32) << 24
+}
+
+/// Coordinates and colour of a voxel read from a model csv.
+pub type CsvVoxel = Result<(Point3<usize>, u32), Box<dyn std::error::Error>>;
+
+// Grid size encoded in the file name of a model csv, after the last `_`,
+// e.g. 512 for `models/dragon_512.csv`.
+fn csv_grid_size(path: &str) -> Result<usize, Box<dyn std::error::Error>> {
+    let name = std::path::Path::new(path)
+        .file_stem()
+        .and_then(|stem| stem.to_str())
+        .unwrap_or("");
+    let size_start = name.rfind('_').map_or(0, |i| i + 1);
+    name[size_start..]
+        .parse::<usize>()
+        .map_err(|_| format!("no grid size in file name {}", path).into())
+}
+
+// Opens a model csv whose grid size is encoded in the file name, e.g.
+// `dragon_512.csv`. Returns the grid size and an iterator over the voxels.
+pub fn read_csv(
+    path: &str,
+) -> Result<(usize, impl Iterator<Item = CsvVoxel>), Box<dyn std::error::Error>> {
+    let size = csv_grid_size(path)?;
+    let csv_reader = csv::Reader::from_path(path)?;
+    Ok((size, csv_voxels(csv_reader)))
+}
+
+// Rows are `x,y,z`, optionally followed by `r,g,b` or `r,g,b,a` in 0..=255.
+fn csv_voxels<R: Read>(csv_reader: csv::Reader<R>) -> impl Iterator<Item = CsvVoxel> {
+    csv_reader.into_deserialize::<Vec<usize>>().map(|result| {
+        let row = result?;
+        let channel =
+            |i: usize| u8::try_from(row[i]).map_err(|_| format!("invalid colour {}", row[i]));
+        let color = match row.len() {
+            3 => DEFAULT_COLOR,
+            6 => rgba(channel(3)?, channel(4)?, channel(5)?, 0xFF),
+            7 => rgba(channel(3)?, channel(4)?, channel(5)?, channel(6)?),
+            len => return Err(format!("voxel row with {} columns", len).into()),
+        };
+        Ok((Point3::new(row[0], row[1], row[2]), color))
+    })
+}
+
+// Writes the voxels of a grid with the given size as `x,y,z,r,g,b,a` rows,
+// which `read_csv` reads back. Fails unless the file name encodes the grid
+// size, e.g. `dragon_512.csv`.
+pub fn write_csv<I>(path: &str, size: usize, voxels: I) -> Result<(), Box<dyn std::error::Error>>
+where
+    I: IntoIterator<Item = (Point3<usize>, u32)>,
+{
+    let name_size = csv_grid_size(path)?;
+    if name_size != size {
+        return Err(format!("file name {} does not match grid size {}", path, size).into());
+    }
+    write_csv_voxels(csv::Writer::from_path(path)?, voxels)
+}
+
+fn write_csv_voxels<W: Write, I>(
+    mut csv_writer: csv::Writer<W>,
+    voxels: I,
+) -> Result<(), Box<dyn std::error::Error>>
+where
+    I: IntoIterator<Item = (Point3<usize>, u32)>,
+{
+    csv_writer.write_record(["x", "y", "z", "r", "g", "b", "a"])?;
+    for (p, color) in voxels {
+        let channel = |i: usize| ((color >> (8 * i)) & 0xFF) as usize;
+        let row = [
+            p.x,
+            p.y,
+            p.z,
+            channel(0),
+            channel(1),
+            channel(2),
+            channel(3),
+        ];
+        csv_writer.serialize(row)?;
+    }
+    csv_writer.flush()?;
+    Ok(())
+}
 
 pub struct VoxelGrid {
-    pub data: Vec<Vec<Vec<bool>>>,
+    pub data: Vec<Vec<Vec<Option<u32>>>>,
     pub size: usize,
 }
 
 impl VoxelGrid {
+    pub fn new(size: usize) -> VoxelGrid {
+        VoxelGrid {
+            data: vec![vec![vec![None; size]; size]; size],
+            size,
+        }
+    }
+
     pub fn sample(&self, x: usize, y: usize, z: usize, size: usize) -> bool {
         for x1 in x..x + size {
             for y1 in y..y + size {
                 for z1 in z..z + size {
-                    if self.data[x1][y1][z1] {
+                    if self.data[x1][y1][z1].is_some() {
                         return true;
                     }
                 }
@@ -20,16 +121,185 @@ impl VoxelGrid {
     }
 
     pub fn from_csv(path: String) -> Result<VoxelGrid, Box<dyn std::error::Error>> {
-        let mut csv_reader = csv::Reader::from_path(&path)?;
-        let size_start = path.find("_").unwrap() + 1;
-        let size_end = path.find(".").unwrap();
-        let size_str = &path[size_start..size_end];
-        let size = size_str.parse::<usize>().unwrap();
-        let mut data = vec![vec![vec![false; size]; size]; size];
-        for result in csv_reader.deserialize() {
-            let coords: Point3<usize> = result?;
-            data[coords.x][coords.y][coords.z] = true;
-        }
-        Ok(VoxelGrid { data, size })
+        let (size, voxels) = read_csv(&path)?;
+        let mut voxel_grid = VoxelGrid::new(size);
+        for result in voxels {
+            let (coords, color) = result?;
+            voxel_grid.data[coords.x][coords.y][coords.z] = Some(color);
+        }
+        Ok(voxel_grid)
+    }
+}
+
+impl Raycastable for VoxelGrid {
+    /// Walks through the grid one voxel at a time with a 3D-DDA. The grid
+    /// spans [0, 1]^3 like the octree, and hits follow the same conventions.
+    /// There are no coarser levels, so the footprint is ignored and the walk
+    /// always stops at a voxel.
+    fn raycast_query(&self, query: &RayQuery) -> Option<RaycastHit> {
+        let (origin, dir) = (query.origin, query.dir);
+        // clip the ray to the grid
+        let (mut t, mut t_exit, mut entry_axis) = (query.t_min, f32::INFINITY, None);
+        for i in 0..3 {
+            if dir[i] == 0.0 {
+                if origin[i] < 0.0 || origin[i] > 1.0 {
+                    return None;
+                }
+                continue;
+            }
+            let t0 = -origin[i] / dir[i];
+            let t1 = (1.0 - origin[i]) / dir[i];
+            if t0.min(t1) > t {
+                t = t0.min(t1);
+                entry_axis = Some(i);
+            }
+            t_exit = t_exit.min(t0.max(t1));
+        }
+        if t > t_exit || t > query.t_max {
+            return None;
+        }
+
+        // The voxel the ray enters at t. On a boundary between two voxels it
+        // is the one in the direction of the ray.
+        let size = self.size as f32;
+        let start = origin + t * dir;
+        let mut voxel = Vector3::from_fn(|i, _| {
+            let v = (start[i] * size).floor();
+            let v = if dir[i] < 0.0 && v == start[i] * size {
+                v - 1.0
+            } else {
+                v
+            };
+            v.max(0.0).min(size - 1.0) as isize
+        });
+        let mut normal = Vector3::zeros();
+        if let Some(axis) = entry_axis {
+            voxel[axis] = if dir[axis] > 0.0 {
+                0
+            } else {
+                self.size as isize - 1
+            };
+            normal[axis] = -dir[axis].signum();
+        }
+
+        let step = dir.map(|v| if v > 0.0 { 1 } else { -1 });
+        loop {
+            let (x, y, z) = (voxel.x as usize, voxel.y as usize, voxel.z as usize);
+            if let Some(color) = self.data[x][y][z] {
+                return Some(RaycastHit {
+                    color,
+                    normal,
+                    pos: origin + t * dir,
+                    t,
+                    voxel: Point3::new(x, y, z),
+                    depth: self.size.trailing_zeros() as usize,
+                    node: (x * self.size + y) * self.size + z,
+                });
+            }
+            // Step to the neighbour whose boundary is crossed first. The
+            // crossings are computed from the voxel coordinates rather than
+            // accumulated, so they do not drift.
+            let crossing = |i: usize| {
+                if dir[i] == 0.0 {
+                    return f32::INFINITY;
+                }
+                let boundary = (voxel[i] + (step[i] + 1) / 2) as f32 / size;
+                (boundary - origin[i]) / dir[i]
+            };
+            let axis = (0..3)
+                .min_by(|&a, &b| crossing(a).partial_cmp(&crossing(b)).unwrap())
+                .unwrap();
+            t = crossing(axis);
+            if t > query.t_max {
+                return None;
+            }
+            voxel[axis] += step[axis];
+            if voxel[axis] < 0 || voxel[axis] >= self.size as isize {
+                return None;
+            }
+            normal = Vector3::zeros();
+            normal[axis] = -step[axis] as f32;
+        }
+    }
+}
+
+#[test]
+fn test_csv_colors() {
+    let data = "x,y,z,r,g,b\n1,2,3,10,20,30\n";
+    let voxels: Vec<_> = csv_voxels(csv::Reader::from_reader(data.as_bytes()))
+        .map(|v| v.unwrap())
+        .collect();
+    assert_eq!(voxels, vec![(Point3::new(1, 2, 3), rgba(10, 20, 30, 255))]);
+
+    let data = "x,y,z,r,g,b,a\n1,2,3,10,20,30,40\n";
+    let voxels: Vec<_> = csv_voxels(csv::Reader::from_reader(data.as_bytes()))
+        .map(|v| v.unwrap())
+        .collect();
+    assert_eq!(voxels, vec![(Point3::new(1, 2, 3), rgba(10, 20, 30, 40))]);
+
+    let data = "x,y,z\n4,5,6\n";
+    let voxels: Vec<_> = csv_voxels(csv::Reader::from_reader(data.as_bytes()))
+        .map(|v| v.unwrap())
+        .collect();
+    assert_eq!(voxels, vec![(Point3::new(4, 5, 6), DEFAULT_COLOR)]);
+
+    let data = "x,y,z,r,g,b\n4,5,6,256,0,0\n";
+    assert!(csv_voxels(csv::Reader::from_reader(data.as_bytes())).all(|v| v.is_err()));
+}
+
+#[test]
+fn test_csv_roundtrip() {
+    let voxels = vec![
+        (Point3::new(1, 2, 3), rgba(10, 20, 30, 40)),
+        (Point3::new(0, 0, 0), DEFAULT_COLOR),
+    ];
+    let mut data = Vec::new();
+    write_csv_voxels(csv::Writer::from_writer(&mut data), voxels.clone()).unwrap();
+    let read: Vec<_> = csv_voxels(csv::Reader::from_reader(&data[..]))
+        .map(|v| v.unwrap())
+        .collect();
+    assert_eq!(read, voxels);
+}
+
+#[test]
+fn test_grid_raycast() {
+    use crate::svo::{assert_same_raycasts, brute_force_raycast, random_grid, SparseVoxelOctree};
+    use rand::{Rng, SeedableRng};
+
+    let size = 16;
+    let mut rng = rand::rngs::StdRng::seed_from_u64(22);
+    let voxel_grid = random_grid(&mut rng, size, 0.03);
+    let svo = SparseVoxelOctree::try_from(&voxel_grid).unwrap();
+    assert_same_raycasts(&voxel_grid, &svo, 22);
+
+    // axis-aligned rays and rays starting inside of the grid
+    for i in 0..2000 {
+        let origin = Point3::new(
+            rng.gen_range(-0.5..1.5),
+            rng.gen_range(-0.5..1.5),
+            rng.gen_range(-0.5..1.5),
+        );
+        let mut dir = Vector3::zeros();
+        if i % 2 == 0 {
+            dir[i / 2 % 3] = if rng.gen() { 1.0 } else { -1.0 };
+        } else {
+            dir = Vector3::new(
+                rng.gen_range(-1.0..1.0),
+                rng.gen_range(-1.0..1.0),
+                rng.gen_range(-1.0..1.0),
+            );
+        }
+        let hit = voxel_grid.raycast(origin, dir);
+        match (hit, brute_force_raycast(&voxel_grid, origin, dir, false)) {
+            (None, None) => (),
+            (Some(hit), Some((t, voxel))) => {
+                assert_eq!(Some(hit.color), voxel_grid.data[voxel.x][voxel.y][voxel.z]);
+                assert!((hit.pos - (origin + t * dir)).norm() < 1e-5);
+                let octree_hit = svo.raycast(origin, dir).unwrap();
+                assert_eq!(hit.color, octree_hit.color);
+                assert_eq!(hit.normal, octree_hit.normal);
+            }
+            _ => panic!("hit mismatch for ray {} {}", origin, dir),
+        }
     }
 }